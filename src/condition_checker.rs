@@ -1,51 +1,872 @@
-use crate::sql_parser;
+use crate::csv_reader::Record;
+use crate::sql_parser::{BinaryOperator, Expr, UnaryOperator};
+use crate::value::Value;
+use regex::{Regex, RegexBuilder};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashSet;
 
-// Modified helper function to evaluate compound WHERE clause with AND/OR
-pub fn check_condition(
-    command: &sql_parser::ParsedCommand,
-    headers: &[String],
-    record: &[&str],
-) -> bool {
-    if let Some(cond) = &command.condition {
-        // Split conditions on OR, then split each OR clause on AND
-        cond.split("OR").any(|or_clause| {
-            or_clause.split("AND").all(|and_clause| {
-                let trimmed_clause = and_clause.trim();
-                // Evaluate the condition here (assuming a function `evaluate_condition`)
-                evaluate_condition(trimmed_clause, headers, record)
-            })
+// IN lists of literals longer than this are matched through a hash set
+const MAX_LINEAR_IN_LIST: usize = 16;
+
+// A resolved operand, with the string literal it was written as if there is one
+type Term<'a> = (&'a Value, Option<&'a StringLiteral>);
+
+/// A row that compiled conditions and operands read by slot: the fields of a record, or
+/// the key and aggregate results of a group.
+pub trait Row {
+    /// The typed value in a slot.
+    fn value(&self, slot: usize) -> Value;
+
+    /// The text in a slot as written, or None if it is NULL.
+    fn text(&self, slot: usize) -> Option<Cow<'_, str>>;
+}
+
+impl Row for Record<'_> {
+    fn value(&self, slot: usize) -> Value {
+        Record::value(self, slot)
+    }
+
+    fn text(&self, slot: usize) -> Option<Cow<'_, str>> {
+        self.field_or_null(slot).map(Cow::Borrowed)
+    }
+}
+
+/// A group of an aggregate query: the slots of its key fields, where NULL is an empty
+/// field, followed by one slot per aggregate result.
+pub struct GroupRow<'a> {
+    pub key: &'a [String],
+    pub results: &'a [Value],
+}
+
+impl Row for GroupRow<'_> {
+    fn value(&self, slot: usize) -> Value {
+        match self.key.get(slot) {
+            Some(field) => Value::parse(field),
+            None => self.results[slot - self.key.len()].clone(),
+        }
+    }
+
+    fn text(&self, slot: usize) -> Option<Cow<'_, str>> {
+        match self.key.get(slot) {
+            Some(field) if field.is_empty() => None,
+            Some(field) => Some(Cow::Borrowed(field)),
+            None => match &self.results[slot - self.key.len()] {
+                Value::Null => None,
+                value => Some(Cow::Owned(value.to_string())),
+            },
+        }
+    }
+}
+
+/// What the names in an expression resolve to when it is compiled: each column to its
+/// slot, and each aggregate call, by its text such as `SUM(price)`, to a slot after the
+/// columns.
+pub struct Scope<'a> {
+    pub columns: &'a [String],
+    pub aggregates: &'a [String],
+    pub clause: &'a str, // Where the expression appears, for error messages
+}
+
+/// A WHERE clause compiled against the headers of a file.
+///
+/// Column names are resolved to field indices and literals are parsed once, so matching a
+/// record only parses the fields the condition reads.
+#[derive(Debug)]
+pub enum Predicate {
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+    Not(Box<Predicate>),
+    Compare {
+        left: Operand,
+        op: BinaryOperator,
+        right: Operand,
+    },
+    InList {
+        operand: Operand,
+        list: Vec<Operand>,
+        negated: bool,
+    },
+    InSet {
+        operand: Operand,
+        keys: HashSet<String>,
+        contains_null: bool,
+        negated: bool,
+    },
+    Between {
+        operand: Operand,
+        low: Operand,
+        high: Operand,
+        negated: bool,
+    },
+    Matches {
+        operand: Operand,
+        pattern: Regex,
+        negated: bool,
+    },
+    IsNull {
+        operand: Operand,
+        negated: bool,
+    },
+}
+
+/// A scalar expression compiled against the headers of a file, such as a comparison
+/// operand or a select list item.
+#[derive(Debug)]
+pub enum Operand {
+    Column(usize),
+    Value(Value),
+    String(StringLiteral),
+    Arithmetic {
+        left: Box<Operand>,
+        op: BinaryOperator,
+        right: Box<Operand>,
+    },
+    Negate(Box<Operand>),
+}
+
+/// A string literal together with its typed interpretation.
+#[derive(Debug)]
+pub struct StringLiteral {
+    text: String,
+    typed: Value,
+}
+
+impl StringLiteral {
+    fn new(text: &str) -> Self {
+        StringLiteral {
+            text: text.to_string(),
+            // An empty string is a value, not NULL
+            typed: match Value::parse(text) {
+                Value::Null => Value::Text(text.to_string()),
+                typed => typed,
+            },
+        }
+    }
+}
+
+impl Predicate {
+    /// Compiles a WHERE clause, failing on unknown columns and unsupported expressions.
+    pub fn compile(condition: &Expr, headers: &[String]) -> Result<Predicate, String> {
+        let scope = Scope {
+            columns: headers,
+            aggregates: &[],
+            clause: "WHERE",
+        };
+        Predicate::compile_in(condition, &scope)
+    }
+
+    /// Compiles a condition against a scope, e.g. a HAVING clause against the group key
+    /// and aggregates of a query. Expressions that are not conditions are rejected.
+    pub fn compile_in(condition: &Expr, scope: &Scope) -> Result<Predicate, String> {
+        Ok(match condition {
+            Expr::BinaryOp {
+                left,
+                op: BinaryOperator::And,
+                right,
+            } => Predicate::And(
+                Box::new(Predicate::compile_in(left, scope)?),
+                Box::new(Predicate::compile_in(right, scope)?),
+            ),
+            Expr::BinaryOp {
+                left,
+                op: BinaryOperator::Or,
+                right,
+            } => Predicate::Or(
+                Box::new(Predicate::compile_in(left, scope)?),
+                Box::new(Predicate::compile_in(right, scope)?),
+            ),
+            Expr::UnaryOp {
+                op: UnaryOperator::Not,
+                expr,
+            } => Predicate::Not(Box::new(Predicate::compile_in(expr, scope)?)),
+            Expr::BinaryOp { op, .. } if op.is_arithmetic() => {
+                return Err(format!("Unsupported condition: {}", condition))
+            }
+            Expr::BinaryOp { left, op, right } => match compile_pattern(*op, right)? {
+                Some((pattern, negated)) => Predicate::Matches {
+                    operand: Operand::compile_in(left, scope)?,
+                    pattern,
+                    negated,
+                },
+                None => Predicate::Compare {
+                    left: Operand::compile_in(left, scope)?,
+                    op: *op,
+                    right: Operand::compile_in(right, scope)?,
+                },
+            },
+            Expr::InList {
+                expr,
+                list,
+                negated,
+            } => {
+                let operand = Operand::compile_in(expr, scope)?;
+                let list = list
+                    .iter()
+                    .map(|item| Operand::compile_in(item, scope))
+                    .collect::<Result<Vec<_>, _>>()?;
+                // A 'NaN' literal has no key but still equals NaN as text, so it stays linear
+                if list.len() > MAX_LINEAR_IN_LIST
+                    && list.iter().all(|item| {
+                        let value = item.literal_value();
+                        matches!(item, Operand::Value(_) | Operand::String(_))
+                            && (value.is_null() || value.equality_key().is_some())
+                    })
+                {
+                    let keys = list
+                        .iter()
+                        .filter_map(|item| item.literal_value().equality_key())
+                        .collect();
+                    let contains_null = list.iter().any(|item| item.literal_value().is_null());
+                    Predicate::InSet {
+                        operand,
+                        keys,
+                        contains_null,
+                        negated: *negated,
+                    }
+                } else {
+                    Predicate::InList {
+                        operand,
+                        list,
+                        negated: *negated,
+                    }
+                }
+            }
+            Expr::Between {
+                expr,
+                low,
+                high,
+                negated,
+            } => Predicate::Between {
+                operand: Operand::compile_in(expr, scope)?,
+                low: Operand::compile_in(low, scope)?,
+                high: Operand::compile_in(high, scope)?,
+                negated: *negated,
+            },
+            Expr::IsNull { expr, negated } => Predicate::IsNull {
+                operand: Operand::compile_in(expr, scope)?,
+                negated: *negated,
+            },
+            _ => return Err(format!("Unsupported condition: {}", condition)),
         })
+    }
+
+    /// Returns true if the row satisfies the condition, and false if it does not or if
+    /// the result is unknown because of NULLs.
+    pub fn matches<R: Row + ?Sized>(&self, record: &R) -> bool {
+        self.evaluate(record) == Some(true)
+    }
+
+    // Evaluates the condition with SQL's three-valued logic, with None for unknown.
+    // AND and OR short-circuit.
+    fn evaluate<R: Row + ?Sized>(&self, record: &R) -> Option<bool> {
+        match self {
+            Predicate::And(left, right) => and(left.evaluate(record), || right.evaluate(record)),
+            Predicate::Or(left, right) => or(left.evaluate(record), || right.evaluate(record)),
+            Predicate::Not(predicate) => predicate.evaluate(record).map(|result| !result),
+            Predicate::Compare { left, op, right } => compare(
+                (&left.value(record), left.literal()),
+                *op,
+                (&right.value(record), right.literal()),
+            ),
+            Predicate::InList {
+                operand,
+                list,
+                negated,
+            } => {
+                let values: Vec<_> = list.iter().map(|item| item.value(record)).collect();
+                in_list(
+                    (&operand.value(record), operand.literal()),
+                    values
+                        .iter()
+                        .zip(list)
+                        .map(|(v, item)| (v.as_ref(), item.literal())),
+                    *negated,
+                )
+            }
+            Predicate::InSet {
+                operand,
+                keys,
+                contains_null,
+                negated,
+            } => {
+                let value = operand.value(record);
+                if value.is_null() {
+                    return None;
+                }
+                let found = match value.equality_key() {
+                    Some(key) => keys.contains(&key),
+                    None => false,
+                };
+                match (found, contains_null) {
+                    (false, true) => None,
+                    _ => Some(found != *negated),
+                }
+            }
+            Predicate::Between {
+                operand,
+                low,
+                high,
+                negated,
+            } => between(
+                (&operand.value(record), operand.literal()),
+                (&low.value(record), low.literal()),
+                (&high.value(record), high.literal()),
+                *negated,
+            ),
+            Predicate::Matches {
+                operand,
+                pattern,
+                negated,
+            } => operand
+                .text(record)
+                .map(|text| pattern.is_match(&text) != *negated),
+            Predicate::IsNull { operand, negated } => Some(operand.is_null(record) != *negated),
+        }
+    }
+}
+
+impl Operand {
+    /// Compiles a scalar expression; `clause` names where it appears in error messages.
+    pub fn compile(operand: &Expr, headers: &[String], clause: &str) -> Result<Operand, String> {
+        let scope = Scope {
+            columns: headers,
+            aggregates: &[],
+            clause,
+        };
+        Operand::compile_in(operand, &scope)
+    }
+
+    /// Compiles a scalar expression against a scope.
+    pub fn compile_in(operand: &Expr, scope: &Scope) -> Result<Operand, String> {
+        let clause = scope.clause;
+        match operand {
+            Expr::Column(column_name) => scope
+                .columns
+                .iter()
+                .position(|h| h == column_name)
+                .map(Operand::Column)
+                .ok_or_else(|| format!("Unknown column in {}: {}", clause, column_name)),
+            Expr::Function { .. } => {
+                let label = operand.to_string();
+                match scope.aggregates.iter().position(|a| *a == label) {
+                    Some(i) => Ok(Operand::Column(scope.columns.len() + i)),
+                    None => Err(format!("Unsupported operand in {}: {}", clause, operand)),
+                }
+            }
+            Expr::String(s) => Ok(Operand::String(StringLiteral::new(s))),
+            Expr::Number(_) | Expr::Null => Ok(Operand::Value(literal_value(operand))),
+            Expr::BinaryOp { left, op, right } if op.is_arithmetic() => Ok(Operand::Arithmetic {
+                left: Box::new(Operand::compile_in(left, scope)?),
+                op: *op,
+                right: Box::new(Operand::compile_in(right, scope)?),
+            }),
+            Expr::UnaryOp {
+                op: UnaryOperator::Minus,
+                expr,
+            } => Ok(Operand::Negate(Box::new(Operand::compile_in(expr, scope)?))),
+            _ => Err(format!("Unsupported operand in {}: {}", clause, operand)),
+        }
+    }
+
+    /// Evaluates the expression for a row.
+    pub fn value<'a, R: Row + ?Sized>(&'a self, record: &R) -> Cow<'a, Value> {
+        match self {
+            Operand::Column(index) => Cow::Owned(record.value(*index)),
+            Operand::Value(value) => Cow::Borrowed(value),
+            Operand::String(literal) => Cow::Borrowed(&literal.typed),
+            Operand::Arithmetic { left, op, right } => {
+                Cow::Owned(arithmetic(&left.value(record), *op, &right.value(record)))
+            }
+            Operand::Negate(operand) => {
+                Cow::Owned(operand.value(record).negate().unwrap_or(Value::Null))
+            }
+        }
+    }
+
+    /// The text of an operand as written, which is what patterns match against and what
+    /// is output, or None if it is NULL.
+    pub fn text<'a, R: Row + ?Sized>(&'a self, record: &'a R) -> Option<Cow<'a, str>> {
+        match self {
+            Operand::Column(index) => record.text(*index),
+            Operand::String(literal) => Some(Cow::Borrowed(&literal.text)),
+            _ => match self.value(record).as_ref() {
+                Value::Null => None,
+                value => Some(Cow::Owned(value.to_string())),
+            },
+        }
+    }
+
+    fn is_null<R: Row + ?Sized>(&self, record: &R) -> bool {
+        match self {
+            Operand::Column(index) => record.text(*index).is_none(),
+            _ => self.value(record).is_null(),
+        }
+    }
+
+    fn literal(&self) -> Option<&StringLiteral> {
+        match self {
+            Operand::String(literal) => Some(literal),
+            _ => None,
+        }
+    }
+
+    // The value of a literal operand
+    fn literal_value(&self) -> &Value {
+        match self {
+            Operand::Value(value) => value,
+            Operand::String(literal) => &literal.typed,
+            _ => &Value::Null,
+        }
+    }
+}
+
+// Applies an arithmetic operator; the result is NULL if either side is NULL or not a number
+fn arithmetic(left: &Value, op: BinaryOperator, right: &Value) -> Value {
+    match op {
+        BinaryOperator::Plus => left.add(right),
+        BinaryOperator::Minus => left.subtract(right),
+        BinaryOperator::Multiply => left.multiply(right),
+        BinaryOperator::Divide => left.divide(right),
+        BinaryOperator::Modulo => left.remainder(right),
+        _ => None,
+    }
+    .unwrap_or(Value::Null)
+}
+
+// Three-valued AND: false if either side is false, otherwise unknown if either is unknown
+fn and(left: Option<bool>, right: impl FnOnce() -> Option<bool>) -> Option<bool> {
+    if left == Some(false) {
+        return left;
+    }
+    match right() {
+        Some(true) => left,
+        right => right,
+    }
+}
+
+// Three-valued OR: true if either side is true, otherwise unknown if either is unknown
+fn or(left: Option<bool>, right: impl FnOnce() -> Option<bool>) -> Option<bool> {
+    if left == Some(true) {
+        return left;
+    }
+    match right() {
+        Some(false) => left,
+        right => right,
+    }
+}
+
+// Compiles the pattern of a LIKE, ILIKE or regular expression match into a regex, along
+// with whether the match is negated. Returns None for other operators.
+fn compile_pattern(op: BinaryOperator, pattern: &Expr) -> Result<Option<(Regex, bool)>, String> {
+    let (like, case_insensitive, negated) = match op {
+        BinaryOperator::Like => (true, false, false),
+        BinaryOperator::NotLike => (true, false, true),
+        BinaryOperator::ILike => (true, true, false),
+        BinaryOperator::NotILike => (true, true, true),
+        BinaryOperator::RegexMatch => (false, false, false),
+        BinaryOperator::RegexIMatch => (false, true, false),
+        BinaryOperator::RegexNotMatch => (false, false, true),
+        BinaryOperator::RegexNotIMatch => (false, true, true),
+        _ => return Ok(None),
+    };
+    let text = match pattern {
+        Expr::String(text) => text,
+        _ => return Err(format!("Pattern must be a string literal: {}", pattern)),
+    };
+    let regex = if like {
+        like_to_regex(text)
     } else {
-        true
-    }
-}
-
-// Helper function to evaluate a single condition
-pub fn evaluate_condition(condition: &str, headers: &[String], record: &[&str]) -> bool {
-    let parts: Vec<&str> = condition.split_whitespace().collect();
-    if parts.len() == 3 {
-        let column_name = parts[0];
-        let operator = parts[1];
-        let value: f64 = parts[2].parse().unwrap_or(f64::NAN);
-
-        if let Some(column_index) = headers.iter().position(|h| h == column_name) {
-            let field_value: f64 = record
-                .get(column_index)
-                .unwrap_or(&"")
-                .trim()
-                .parse()
-                .unwrap_or(f64::NAN);
-
-            return match operator {
-                "<" => field_value < value,
-                ">" => field_value > value,
-                "<=" => field_value <= value,
-                ">=" => field_value >= value,
-                "=" => field_value == value,
-                "!=" => field_value != value,
-                _ => false,
-            };
-        }
-    }
-    false
+        text.clone()
+    };
+    RegexBuilder::new(&regex)
+        .case_insensitive(case_insensitive)
+        .build()
+        .map(|regex| Some((regex, negated)))
+        .map_err(|err| format!("Invalid pattern {}: {}", pattern, err))
+}
+
+// Translates a LIKE pattern into an anchored regex: `%` matches any run of characters,
+// `_` any single character, and a backslash makes the next character literal
+fn like_to_regex(pattern: &str) -> String {
+    let mut regex = String::from("(?s)^");
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '%' => regex.push_str(".*"),
+            '_' => regex.push('.'),
+            '\\' => {
+                let literal = chars.next().unwrap_or('\\');
+                regex.push_str(&regex::escape(literal.encode_utf8(&mut [0; 4])));
+            }
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+// Compares two terms, coercing string literals to the type of the other side. The result
+// is unknown if either side is NULL.
+fn compare(left: Term, op: BinaryOperator, right: Term) -> Option<bool> {
+    if left.0.is_null() || right.0.is_null() {
+        return None;
+    }
+    Some(match (left, right) {
+        ((value, _), (_, Some(literal))) => compare_with_literal(value, op, literal, false),
+        ((_, Some(literal)), (value, _)) => compare_with_literal(value, op, literal, true),
+        ((left, None), (right, None)) => evaluate_condition(left, op, right),
+    })
+}
+
+// `x IN (a, b, ...)` is `x = a OR x = b OR ...`, and NOT IN is its negation. So if x is
+// NULL, or x equals no item but the list contains NULL, the result is unknown.
+fn in_list<'a>(value: Term, items: impl Iterator<Item = Term<'a>>, negated: bool) -> Option<bool> {
+    let mut found = Some(false);
+    for item in items {
+        found = or(found, || compare(value, BinaryOperator::Eq, item));
+        if found == Some(true) {
+            break;
+        }
+    }
+    found.map(|found| found != negated)
+}
+
+// `x BETWEEN low AND high` is `low <= x AND x <= high`, and NOT BETWEEN is its negation
+fn between(value: Term, low: Term, high: Term, negated: bool) -> Option<bool> {
+    and(compare(value, BinaryOperator::GtEq, low), || {
+        compare(value, BinaryOperator::LtEq, high)
+    })
+    .map(|inside| inside != negated)
+}
+
+// Compares a value with a string literal, which is on the left if `literal_first`.
+// The literal is typed like the value, so that '2024-01-31' compares as a date and '42'
+// as a number. If the two cannot be compared that way, both sides compare as text.
+fn compare_with_literal(
+    value: &Value,
+    op: BinaryOperator,
+    literal: &StringLiteral,
+    literal_first: bool,
+) -> bool {
+    let (value, literal) = if value.compare(&literal.typed).is_some() {
+        (Cow::Borrowed(value), Cow::Borrowed(&literal.typed))
+    } else {
+        (
+            Cow::Owned(Value::Text(value.to_string())),
+            Cow::Owned(Value::Text(literal.text.clone())),
+        )
+    };
+    if literal_first {
+        evaluate_condition(&literal, op, &value)
+    } else {
+        evaluate_condition(&value, op, &literal)
+    }
+}
+
+// Helper function to evaluate a single comparison; comparisons with NULL or between
+// incomparable types are false
+pub fn evaluate_condition(field_value: &Value, operator: BinaryOperator, value: &Value) -> bool {
+    let ordering = match field_value.compare(value) {
+        Some(ordering) => ordering,
+        None => return false,
+    };
+    match operator {
+        BinaryOperator::Lt => ordering == Ordering::Less,
+        BinaryOperator::Gt => ordering == Ordering::Greater,
+        BinaryOperator::LtEq => ordering != Ordering::Greater,
+        BinaryOperator::GtEq => ordering != Ordering::Less,
+        BinaryOperator::Eq => ordering == Ordering::Equal,
+        BinaryOperator::NotEq => ordering != Ordering::Equal,
+        _ => false,
+    }
+}
+
+// Resolves a literal operand to a value; string literals are always text
+fn literal_value(operand: &Expr) -> Value {
+    match operand {
+        Expr::Number(n) => Value::parse(n),
+        Expr::String(s) => Value::Text(s.clone()),
+        _ => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv_reader::{CsvConfig, CsvReader};
+    use crate::sql_parser::parse_query;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+    // Rows that conditions are evaluated on; an empty field is NULL
+    const TABLE: &str = "v,w\n\
+        1,1\n\
+        2.50,x\n\
+        ,1\n\
+        abc,\n\
+        NaN,NaN\n\
+        2024-01-31,2024-01-31 00:00:00\n\
+        true,1e0\n";
+
+    fn condition(sql: &str) -> Expr {
+        let query = parse_query(&format!("SELECT * FROM t.csv WHERE {}", sql)).unwrap();
+        query.selection.unwrap()
+    }
+
+    // Evaluates a compiled condition on each row of TABLE
+    fn evaluate_rows(predicate: &Predicate) -> Vec<Option<bool>> {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "csvsql-condition-{}-{}.csv",
+            std::process::id(),
+            FILES.fetch_add(1, Relaxed)
+        ));
+        fs::write(&path, TABLE).unwrap();
+        let config = CsvConfig {
+            delimiter: None,
+            quote: b'"',
+            escape: None,
+            has_header: true,
+            columns: None,
+            null_values: Vec::new(),
+        };
+        let reader = CsvReader::new(path.to_str().unwrap(), &config).unwrap();
+        let results = reader
+            .records()
+            .map(|record| predicate.evaluate(&record.unwrap()))
+            .collect();
+        fs::remove_file(&path).unwrap();
+        results
+    }
+
+    fn compile(sql: &str) -> Predicate {
+        Predicate::compile(&condition(sql), &["v".to_string(), "w".to_string()]).unwrap()
+    }
+
+    const T: Option<bool> = Some(true);
+    const F: Option<bool> = Some(false);
+    const U: Option<bool> = None;
+
+    #[test]
+    fn and_or_follow_three_valued_logic() {
+        for (left, right, and_result, or_result) in [
+            (T, T, T, T),
+            (T, F, F, T),
+            (T, U, U, T),
+            (F, T, F, T),
+            (F, F, F, F),
+            (F, U, F, U),
+            (U, T, U, T),
+            (U, F, F, U),
+            (U, U, U, U),
+        ] {
+            assert_eq!(
+                and(left, || right),
+                and_result,
+                "{:?} AND {:?}",
+                left,
+                right
+            );
+            assert_eq!(or(left, || right), or_result, "{:?} OR {:?}", left, right);
+        }
+        // The right side is not evaluated when the left decides
+        assert_eq!(and(F, || unreachable!()), F);
+        assert_eq!(or(T, || unreachable!()), T);
+    }
+
+    #[test]
+    fn conditions_treat_null_as_unknown() {
+        let cases = [
+            ("v IS NULL", [F, F, T, F, F, F, F]),
+            ("w IS NOT NULL", [T, T, T, F, T, T, T]),
+            ("v = 1", [T, F, U, F, F, F, F]),
+            ("NOT v = 1", [F, T, U, T, T, T, T]),
+            ("v = 1 OR w = 1", [T, F, T, U, F, F, T]),
+            ("v = 1 AND w IS NULL", [F, F, F, F, F, F, F]),
+            ("v IS NULL AND w = 1", [F, F, T, F, F, F, F]),
+            ("v IN (1, 2.5)", [T, T, U, F, F, F, F]),
+            ("v IN (1, NULL)", [T, U, U, U, U, U, U]),
+            ("v NOT IN (1, NULL)", [F, U, U, U, U, U, U]),
+            ("v NOT IN (1, 'abc')", [F, T, U, F, T, T, T]),
+            ("v BETWEEN 1 AND 3", [T, T, U, F, F, F, F]),
+            ("v NOT BETWEEN 1 AND 2", [F, T, U, T, T, T, T]),
+            ("v BETWEEN NULL AND 2", [U, F, U, F, F, F, F]),
+            ("v BETWEEN 'a' AND 'b'", [F, F, U, T, F, F, F]),
+            ("v = '2024-01-31'", [F, F, U, F, F, T, F]),
+            ("w = '2024-01-31'", [F, F, F, U, F, T, F]),
+            ("w = v", [T, F, U, U, F, T, F]),
+            ("v = 'NaN'", [F, F, U, F, T, F, F]),
+        ];
+        for (sql, expected) in cases {
+            assert_eq!(evaluate_rows(&compile(sql)), expected, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn in_list_and_in_set_agree() {
+        let lists = [
+            "1, 2.5, 'abc'",
+            "'1', '2.500', 'ABC'",
+            "NULL, 1, 'x'",
+            "'2024-01-31 00:00:00', 'true', '1.0'",
+            "'2024-01-31', '1e0', 2.50",
+            "'NaN', 1, NULL",
+            "'', ' 1', 'abc '",
+        ];
+        // Items that match no row, to push a list past the linear limit
+        let filler: String = (0..17).map(|i| format!(", 'f{}'", i)).collect();
+        for list in lists {
+            for column in ["v", "w"] {
+                for not in ["", "NOT "] {
+                    let short = compile(&format!("{} {}IN ({})", column, not, list));
+                    let long = compile(&format!("{} {}IN ({}{})", column, not, list, filler));
+                    assert!(matches!(short, Predicate::InList { .. }));
+                    assert_eq!(
+                        matches!(long, Predicate::InSet { .. }),
+                        !list.contains("NaN"),
+                        "{}",
+                        list
+                    );
+                    assert_eq!(
+                        evaluate_rows(&short),
+                        evaluate_rows(&long),
+                        "{} {}IN ({})",
+                        column,
+                        not,
+                        list
+                    );
+                }
+            }
+        }
+    }
+
+    // Matches `text` against a pattern, applying the negation of NOT LIKE and friends
+    fn matches(text: &str, op: BinaryOperator, pattern: &str) -> bool {
+        let (regex, negated) = compile_pattern(op, &Expr::String(pattern.to_string()))
+            .unwrap()
+            .unwrap();
+        regex.is_match(text) != negated
+    }
+
+    #[test]
+    fn like_patterns() {
+        use BinaryOperator::{ILike, Like, NotILike, NotLike};
+        let cases = [
+            ("a%", "abc", true),
+            ("a%", "a", true),
+            ("a%", "ba", false),
+            ("%b%", "abc", true),
+            ("_b_", "abc", true),
+            ("_b_", "ab", false),
+            ("_b_", "abcd", false),
+            ("%", "multi\nline", true),
+            ("100\\%", "100%", true),
+            ("100\\%", "1000", false),
+            ("a\\_b", "a_b", true),
+            ("a\\_b", "axb", false),
+            ("a\\\\b", "a\\b", true),
+            ("a\\", "a\\", true),
+            ("a.b", "a.b", true),
+            ("a.b", "axb", false),
+            ("(x)+%", "(x)+y", true),
+            ("(x)+%", "xx", false),
+            ("[ab]*", "[ab]*", true),
+            ("[ab]*", "a", false),
+            ("\u{e9}_", "\u{e9}\u{e8}", true),
+            ("ABC%", "abcdef", false),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(
+                matches(text, Like, pattern),
+                expected,
+                "{:?} LIKE {:?}",
+                text,
+                pattern
+            );
+            assert_eq!(
+                matches(text, NotLike, pattern),
+                !expected,
+                "{:?} NOT LIKE {:?}",
+                text,
+                pattern
+            );
+        }
+        assert!(matches("abcdef", ILike, "ABC%"));
+        assert!(matches("\u{c9}T\u{c9}", ILike, "\u{e9}t\u{e9}"));
+        assert!(!matches("abcdef", NotILike, "abc%"));
+        assert!(!matches("xabc", ILike, "ABC%"));
+    }
+
+    #[test]
+    fn pattern_errors() {
+        assert_eq!(
+            compile_pattern(BinaryOperator::RegexMatch, &Expr::String("(".to_string()))
+                .unwrap_err()
+                .lines()
+                .next(),
+            Some("Invalid pattern '(': regex parse error:")
+        );
+        assert_eq!(
+            compile_pattern(BinaryOperator::Like, &Expr::Column("v".to_string())).unwrap_err(),
+            "Pattern must be a string literal: v"
+        );
+        assert!(compile_pattern(BinaryOperator::Eq, &Expr::Null)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn having_reads_group_keys_and_aggregate_results() {
+        let columns = ["region".to_string()];
+        let aggregates = ["COUNT(*)".to_string(), "SUM(price)".to_string()];
+        let scope = Scope {
+            columns: &columns,
+            aggregates: &aggregates,
+            clause: "HAVING",
+        };
+        let groups = [
+            ("east", Value::Int64(2), Value::Decimal(105, 1)),
+            ("west", Value::Int64(1), Value::Null),
+            ("", Value::Int64(3), Value::Int64(4)),
+        ];
+        let cases = [
+            ("COUNT(*) > 1", [T, F, T]),
+            ("COUNT(*) > 1 AND region LIKE 'e%'", [T, F, U]),
+            ("SUM(price) * 2 = 21", [T, U, F]),
+            ("SUM(price) IS NULL OR region IS NULL", [F, T, T]),
+            ("region IN ('west', 'north')", [F, T, U]),
+            ("SUM(price) BETWEEN COUNT(*) AND 11", [T, U, T]),
+        ];
+        for (sql, expected) in cases {
+            let predicate = Predicate::compile_in(&condition(sql), &scope).unwrap();
+            let results: Vec<Option<bool>> = groups
+                .iter()
+                .map(|(key, count, sum)| {
+                    let key = [key.to_string()];
+                    let results = [count.clone(), sum.clone()];
+                    predicate.evaluate(&GroupRow {
+                        key: &key,
+                        results: &results,
+                    })
+                })
+                .collect();
+            assert_eq!(results, expected, "{}", sql);
+        }
+
+        let errors = [
+            ("COUNT(*)", "Unsupported condition: COUNT(*)"),
+            ("SUM(price) + 1", "Unsupported condition: SUM(price) + 1"),
+            ("region", "Unsupported condition: region"),
+            (
+                "AVG(price) > 1",
+                "Unsupported operand in HAVING: AVG(price)",
+            ),
+            ("name = 'x'", "Unknown column in HAVING: name"),
+        ];
+        for (sql, message) in errors {
+            let error = Predicate::compile_in(&condition(sql), &scope).unwrap_err();
+            assert_eq!(error, message, "{}", sql);
+        }
+    }
 }
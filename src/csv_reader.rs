@@ -1,4 +1,8 @@
+use crate::value::Value;
+use csv::{ByteRecord, ReaderBuilder};
+use memchr::{memchr, memchr_iter};
 use memmap2::Mmap;
+use std::cell::Cell;
 use std::error::Error;
 use std::fs::File;
 use std::io;
@@ -11,64 +15,528 @@ pub fn map_file(file_path: &str) -> io::Result<Mmap> {
     if metadata.len() == 0 {
         return Err(io::Error::new(io::ErrorKind::InvalidData, "File is empty"));
     }
-    unsafe { Mmap::map(&file).map_err(|e| io::Error::new(io::ErrorKind::Other, e)) }
+    unsafe { Mmap::map(&file).map_err(io::Error::other) }
 }
 
-/// A struct that owns the memory-mapped file and provides an iterator for lines.
+// Delimiters tried by auto-detection, in order of preference on ties
+const CANDIDATE_DELIMITERS: &[u8] = b",\t;|";
+// Number of leading bytes and lines looked at when detecting the delimiter
+const DETECT_BYTES: usize = 64 * 1024;
+const DETECT_LINES: usize = 10;
+
+// Smallest chunk worth handing to its own thread
+const MIN_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// CSV format options given on the command line.
+#[derive(Debug, Clone)]
+pub struct CsvConfig {
+    pub delimiter: Option<u8>, // None to detect the delimiter from the file
+    pub quote: u8,
+    pub escape: Option<u8>, // None if quotes inside quoted fields are doubled
+    pub has_header: bool,   // Whether the first record holds the column names
+    pub columns: Option<Vec<String>>, // Column names for files without a header
+    pub null_values: Vec<String>, // Field values read as NULL besides empty fields
+}
+
+/// The delimiter, quote and escape characters of a particular file.
+#[derive(Debug, Clone, Copy)]
+pub struct Dialect {
+    pub delimiter: u8,
+    pub quote: u8,
+    pub escape: Option<u8>,
+}
+
+impl CsvConfig {
+    /// Resolves the dialect of a file, detecting the delimiter if it was not given.
+    pub fn dialect(&self, data: &[u8]) -> Dialect {
+        Dialect {
+            delimiter: self
+                .delimiter
+                .unwrap_or_else(|| detect_delimiter(data, self.quote)),
+            quote: self.quote,
+            escape: self.escape,
+        }
+    }
+}
+
+/// Guesses the delimiter from the first lines of a file.
+///
+/// Occurrences of each candidate outside quoted fields are counted per line. The candidate
+/// found the same, non-zero number of times on every line wins, preferring the one with the
+/// most fields; if none is consistent, the one most frequent in the first line is used.
+pub fn detect_delimiter(data: &[u8], quote: u8) -> u8 {
+    let data = &data[..data.len().min(DETECT_BYTES)];
+    let mut counts: Vec<[usize; CANDIDATE_DELIMITERS.len()]> = Vec::new();
+    let mut line = [0; CANDIDATE_DELIMITERS.len()];
+    let mut in_quotes = false;
+    let mut line_started = false;
+    for &b in data {
+        line_started = b != b'\n' || in_quotes;
+        if b == quote {
+            in_quotes = !in_quotes;
+        } else if b == b'\n' && !in_quotes {
+            counts.push(line);
+            line = [0; CANDIDATE_DELIMITERS.len()];
+            if counts.len() == DETECT_LINES {
+                break;
+            }
+        } else if !in_quotes {
+            if let Some(i) = CANDIDATE_DELIMITERS.iter().position(|&d| d == b) {
+                line[i] += 1;
+            }
+        }
+    }
+    // A final line without a newline is only complete if the whole file was scanned
+    let last_line_complete = line_started && data.len() < DETECT_BYTES;
+    if counts.is_empty() || (counts.len() < DETECT_LINES && last_line_complete) {
+        counts.push(line);
+    }
+
+    let first = counts[0];
+    let consistent = (0..CANDIDATE_DELIMITERS.len())
+        .filter(|&i| first[i] > 0 && counts.iter().all(|line| line[i] == first[i]))
+        .max_by_key(|&i| (first[i], std::cmp::Reverse(i)));
+    let frequent = (0..CANDIDATE_DELIMITERS.len())
+        .filter(|&i| first[i] > 0)
+        .max_by_key(|&i| (first[i], std::cmp::Reverse(i)));
+    consistent
+        .or(frequent)
+        .map_or(b',', |i| CANDIDATE_DELIMITERS[i])
+}
+
+/// A struct that owns the memory-mapped file and provides an iterator for records.
 pub struct CsvReader {
     mmap: Mmap,
+    dialect: Dialect,
+    has_quotes: bool, // Whether any quote character occurs in the file
+    has_header: bool,
+    columns: Option<Vec<String>>,
+    null_values: Vec<String>,
 }
 
 impl CsvReader {
-    pub fn new(file_path: &str) -> Result<Self, Box<dyn Error>> {
+    pub fn new(file_path: &str, config: &CsvConfig) -> Result<Self, Box<dyn Error>> {
         let mmap = map_file(file_path)?;
-        Ok(CsvReader { mmap })
+        let dialect = config.dialect(&mmap);
+        let has_quotes = memchr(dialect.quote, &mmap).is_some();
+        Ok(CsvReader {
+            mmap,
+            dialect,
+            has_quotes,
+            has_header: config.has_header,
+            columns: config.columns.clone(),
+            null_values: config.null_values.clone(),
+        })
+    }
+
+    /// The dialect the file is read with.
+    pub fn dialect(&self) -> &Dialect {
+        &self.dialect
     }
 
-    pub fn lines(&self) -> LineIterator {
-        LineIterator::new(&self.mmap)
+    /// Whether the file contains a quote character, in which case records are parsed
+    /// following RFC 4180 instead of being split on newlines.
+    pub fn has_quotes(&self) -> bool {
+        self.has_quotes
+    }
+
+    /// The contents of the file.
+    pub fn data(&self) -> &[u8] {
+        &self.mmap
+    }
+
+    /// Returns the column names: the header record, or for files without a header the
+    /// configured names, falling back to c1, c2, ... for each field of the first record.
+    pub fn headers(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        if let (false, Some(columns)) = (self.has_header, &self.columns) {
+            return Ok(columns.iter().map(|c| c.trim().to_string()).collect());
+        }
+        let first_record = match self.record_iterator().next() {
+            Some(Ok(record)) => record,
+            // Return an error if the headers cannot be read
+            _ => return Err("Failed to read headers".into()),
+        };
+
+        if self.has_header {
+            Ok(first_record
+                .fields()
+                .map(|s| String::from_utf8_lossy(s).trim().to_string())
+                .collect())
+        } else {
+            Ok((1..=first_record.fields().count())
+                .map(|i| format!("c{}", i))
+                .collect())
+        }
+    }
+
+    /// Iterates over the data records, skipping the header record if there is one.
+    pub fn records(&self) -> RecordIterator<'_> {
+        let mut records = self.record_iterator();
+        if self.has_header {
+            records.next();
+        }
+        records
+    }
+
+    /// Splits the data records into at most `count` consecutive chunks of at least 4 MiB
+    /// that can be read independently, e.g. on separate threads.
+    /// Files with quotes are never split, since a quoted field may contain a newline.
+    pub fn record_chunks(&self, count: usize) -> Vec<RecordIterator<'_>> {
+        let records = self.records();
+        let (start, end) = (records.start, records.end);
+        let count = count.min((end - start) / MIN_CHUNK_SIZE).max(1);
+        if self.has_quotes || count == 1 {
+            return vec![records];
+        }
+
+        let mut chunks = Vec::with_capacity(count);
+        let mut chunk_start = start;
+        for i in 1..=count {
+            // Each chunk ends just after the first newline past its share of the file
+            let target = (start + (end - start) / count * i).max(chunk_start);
+            let chunk_end = match memchr(b'\n', &self.mmap[target..end]) {
+                Some(pos) if i < count => target + pos + 1,
+                _ => end,
+            };
+            chunks.push(RecordIterator {
+                mmap: &self.mmap,
+                start: chunk_start,
+                end: chunk_end,
+                delimiter: self.dialect.delimiter,
+                parser: None,
+                null_values: &self.null_values,
+            });
+            chunk_start = chunk_end;
+            if chunk_start == end {
+                break;
+            }
+        }
+        chunks
+    }
+
+    // Iterates over all records of the file, including the header
+    fn record_iterator(&self) -> RecordIterator<'_> {
+        RecordIterator::new(&self.mmap, self.dialect, self.has_quotes, &self.null_values)
     }
 }
 
-/// An iterator to return lines from the memory-mapped file.
-pub struct LineIterator<'a> {
+/// A single CSV record, with quoted fields already unquoted.
+pub struct Record<'a> {
+    raw: &'a [u8],
+    fields: Fields<'a>,
+    null_values: &'a [String],
+}
+
+// Fields of a record. Unquoted records borrow them straight from the mapped file and are
+// split on demand; the cursor remembers where the last field read starts, so reading
+// fields from left to right scans the line only once.
+enum Fields<'a> {
+    Unquoted {
+        line: &'a [u8],
+        delimiter: u8,
+        cursor: Cell<(usize, usize)>, // Index and start offset of the last field read
+    },
+    Parsed(ByteRecord),
+}
+
+impl<'a> Record<'a> {
+    /// The record as it appears in the file, without the line terminator.
+    pub fn raw(&self) -> &'a [u8] {
+        self.raw
+    }
+
+    /// Returns the field at `index`, if the record has that many fields.
+    pub fn get(&self, index: usize) -> Option<&[u8]> {
+        match &self.fields {
+            Fields::Unquoted {
+                line,
+                delimiter,
+                cursor,
+            } => {
+                let (mut current, mut start) = cursor.get();
+                if index < current {
+                    (current, start) = (0, 0);
+                }
+                loop {
+                    let end =
+                        memchr(*delimiter, &line[start..]).map_or(line.len(), |pos| start + pos);
+                    if current == index {
+                        cursor.set((current, start));
+                        return Some(&line[start..end]);
+                    }
+                    if end == line.len() {
+                        return None;
+                    }
+                    (current, start) = (current + 1, end + 1);
+                }
+            }
+            Fields::Parsed(record) => record.get(index),
+        }
+    }
+
+    /// Returns the field at `index` as text, or an empty string if it is missing.
+    pub fn field(&self, index: usize) -> &str {
+        self.get(index)
+            .and_then(|field| std::str::from_utf8(field).ok())
+            .unwrap_or("")
+    }
+
+    /// Returns the field at `index` as text, or None if it is NULL: missing, blank or one
+    /// of the configured null values.
+    pub fn field_or_null(&self, index: usize) -> Option<&str> {
+        let field = self.field(index);
+        let trimmed = field.trim();
+        if trimmed.is_empty() || self.null_values.iter().any(|null| null == trimmed) {
+            None
+        } else {
+            Some(field)
+        }
+    }
+
+    /// Returns the typed value of the field at `index`.
+    pub fn value(&self, index: usize) -> Value {
+        self.field_or_null(index).map_or(Value::Null, Value::parse)
+    }
+
+    /// Iterates over all fields of the record.
+    pub fn fields(&self) -> impl Iterator<Item = &[u8]> {
+        let count = match &self.fields {
+            Fields::Unquoted {
+                line, delimiter, ..
+            } => memchr_iter(*delimiter, line).count() + 1,
+            Fields::Parsed(record) => record.len(),
+        };
+        (0..count).filter_map(|index| self.get(index))
+    }
+}
+
+/// An iterator over the records of the memory-mapped file.
+///
+/// Files without any quote character are split on newlines and delimiters without copying.
+/// Otherwise records are parsed following RFC 4180, so quoted fields may contain
+/// delimiters, escaped quotes and newlines.
+pub struct RecordIterator<'a> {
     mmap: &'a Mmap,
     start: usize,
     end: usize,
+    delimiter: u8,
+    parser: Option<csv::Reader<&'a [u8]>>, // Quote-aware parser, if the file has quotes
+    null_values: &'a [String],
 }
 
-impl<'a> LineIterator<'a> {
-    fn new(mmap: &'a Mmap) -> Self {
-        LineIterator {
+impl<'a> RecordIterator<'a> {
+    fn new(mmap: &'a Mmap, dialect: Dialect, has_quotes: bool, null_values: &'a [String]) -> Self {
+        let parser = has_quotes.then(|| {
+            ReaderBuilder::new()
+                .has_headers(false)
+                .flexible(true)
+                .delimiter(dialect.delimiter)
+                .quote(dialect.quote)
+                .escape(dialect.escape)
+                .double_quote(dialect.escape.is_none())
+                .from_reader(&mmap[..])
+        });
+        RecordIterator {
             mmap,
             start: 0,
             end: mmap.len(),
+            delimiter: dialect.delimiter,
+            parser,
+            null_values,
         }
     }
+
+    // Reads the next record with the quote-aware parser
+    fn next_parsed(&mut self) -> Option<io::Result<Record<'a>>> {
+        let parser = self.parser.as_mut()?;
+        let mut record = ByteRecord::new();
+        match parser.read_byte_record(&mut record) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(err) => return Some(Err(err.into())),
+        }
+
+        // The raw record spans from its start to the parser's position, which is past the
+        // line terminator. After a CR the record may start at the LF of the terminator.
+        let start = record.position().map_or(0, |p| p.byte() as usize);
+        let end = parser.position().byte() as usize;
+        let mut raw = &self.mmap[start..end.min(self.end)];
+        while let [b'\n' | b'\r', rest @ ..] | [rest @ .., b'\n' | b'\r'] = raw {
+            raw = rest;
+        }
+        Some(Ok(Record {
+            raw,
+            fields: Fields::Parsed(record),
+            null_values: self.null_values,
+        }))
+    }
 }
 
-impl<'a> Iterator for LineIterator<'a> {
-    type Item = io::Result<&'a [u8]>;
+impl<'a> Iterator for RecordIterator<'a> {
+    type Item = io::Result<Record<'a>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.start >= self.end {
-            return None;
+        if self.parser.is_some() {
+            return self.next_parsed();
+        }
+        // Empty lines are skipped, as the quote-aware parser does
+        let line = loop {
+            if self.start >= self.end {
+                return None;
+            }
+            let mmap_slice = &self.mmap[self.start..self.end];
+            let line = match memchr(b'\n', mmap_slice) {
+                Some(pos) => {
+                    self.start += pos + 1;
+                    &mmap_slice[..pos]
+                }
+                None => {
+                    self.start = self.end;
+                    mmap_slice
+                }
+            };
+            let line = trim_line(line);
+            if !line.is_empty() {
+                break line;
+            }
+        };
+
+        Some(Ok(Record {
+            raw: line,
+            fields: Fields::Unquoted {
+                line,
+                delimiter: self.delimiter,
+                cursor: Cell::new((0, 0)),
+            },
+            null_values: self.null_values,
+        }))
+    }
+}
+
+/// Counts the records of a file without quotes, i.e. its lines apart from empty ones.
+pub fn count_unquoted_records(data: &[u8]) -> usize {
+    let mut count = 0;
+    let mut start = 0;
+    for end in memchr_iter(b'\n', data).chain([data.len()]) {
+        if !trim_line(&data[start.min(end)..end]).is_empty() {
+            count += 1;
         }
+        start = end + 1;
+    }
+    count
+}
 
-        let mmap_slice = &self.mmap[self.start..self.end];
-        let mut pos = 0;
+// Removes the carriage return of a CRLF line terminator
+fn trim_line(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // Maps `data` from a temporary file named after the test
+    fn map(name: &str, data: &[u8]) -> Mmap {
+        let path = std::env::temp_dir().join(format!("csvsql-{}-{}.csv", name, std::process::id()));
+        fs::write(&path, data).unwrap();
+        let mmap = map_file(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        mmap
+    }
+
+    // Reads the raw text and the fields of each record
+    fn read(mmap: &Mmap, has_quotes: bool) -> Vec<(String, Vec<String>)> {
+        let dialect = Dialect {
+            delimiter: b',',
+            quote: b'"',
+            escape: None,
+        };
+        RecordIterator::new(mmap, dialect, has_quotes, &[])
+            .map(|record| {
+                let record = record.unwrap();
+                let text = |bytes| String::from_utf8_lossy(bytes).into_owned();
+                (text(record.raw()), record.fields().map(text).collect())
+            })
+            .collect()
+    }
 
-        while pos < mmap_slice.len() {
-            if mmap_slice[pos] == b'\n' {
-                let line = &mmap_slice[..pos];
-                self.start += pos + 1;
-                return Some(Ok(line));
+    #[test]
+    fn unquoted_and_quote_aware_paths_agree() {
+        let cases: &[&[u8]] = &[
+            b"name,n\r\nbob,1\r\nann,2\r\n",
+            b"name,n\r\nbob,1\r\nann,2",
+            b"name,n\n\nbob,1\n\n\nann,2\n",
+            b"name,n\r\n\r\nbob,1\r\n\r\n",
+            b"\nname,n\nbob,\n",
+            b"name\n\n",
+        ];
+        for (i, &data) in cases.iter().enumerate() {
+            let mmap = map(&format!("paths-{}", i), data);
+            let unquoted = read(&mmap, false);
+            assert_eq!(
+                unquoted,
+                read(&mmap, true),
+                "{:?}",
+                String::from_utf8_lossy(data)
+            );
+            for (raw, fields) in &unquoted {
+                assert!(!raw.contains(['\r', '\n']));
+                assert!(fields.iter().all(|field| !field.contains('\r')));
             }
-            pos += 1;
+            assert_eq!(count_unquoted_records(data), unquoted.len());
         }
+    }
+
+    #[test]
+    fn detects_delimiters() {
+        let cases: &[(&[u8], u8)] = &[
+            (b"a,b,c\n1,2,3\n", b','),
+            (b"a\tb\tc\n1\t2\t3\n", b'\t'),
+            (b"a|b\n1|2\n3|4", b'|'),
+            // Decimal commas only appear in the data, so the semicolon is the consistent one
+            (b"price;qty\n1,5;2,3\n10;4\n", b';'),
+            // Commas inside quotes are not counted
+            (b"\"a,b\";c\n\"x,y,z\";w\n", b';'),
+            // Without a header both appear once, and the comma is preferred on ties
+            (b"1,5;2,3", b','),
+            (b"1,5;2,3\n", b','),
+            // A single column has no delimiter, so the default is used
+            (b"name\nbob\nann\n", b','),
+            (b"name", b','),
+            // No candidate is consistent, so the most frequent one in the first line wins
+            (b"a;b;c,d\n1;2\n3,4,5,6\n", b';'),
+            // The comma in the truncated last line does not count against it
+            (b"a,b\n1,2\n3", b','),
+        ];
+        for &(data, expected) in cases {
+            assert_eq!(
+                detect_delimiter(data, b'"') as char,
+                expected as char,
+                "{:?}",
+                String::from_utf8_lossy(data)
+            );
+        }
+    }
 
-        let line = mmap_slice;
-        self.start = self.end;
-        Some(Ok(line))
+    #[test]
+    fn detects_delimiters_from_the_first_lines_only() {
+        let mut data = b"a;b\n".repeat(DETECT_LINES);
+        data.extend_from_slice(b"1,2,3\n");
+        assert_eq!(detect_delimiter(&data, b'"'), b';');
+        // The line cut off at the end of the scanned bytes has the semicolon but neither
+        // pipe; counting it would make the semicolon the only consistent candidate
+        let line = [
+            &[b'x'; 1000][..],
+            b";",
+            &[b'x'; 1000],
+            b"|",
+            &[b'x'; 5096],
+            b"|\n",
+        ]
+        .concat();
+        let data = line.repeat(DETECT_BYTES / line.len() + 2);
+        assert!((1001..2001).contains(&(DETECT_BYTES % line.len())));
+        assert_eq!(detect_delimiter(&data, b'"'), b'|');
     }
 }
@@ -0,0 +1,685 @@
+use crate::condition_checker::{self, GroupRow, Operand, Predicate};
+use crate::csv_reader::{self, Record};
+use crate::sql_parser::{self, Expr, SelectItem};
+use crate::value::Value;
+use crate::{aggregates, sorter};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::{self, Write};
+
+/// Runs a parsed query against its CSV file, writing the result as CSV to `out`.
+pub fn execute(
+    query: &sql_parser::Query,
+    csv_config: &csv_reader::CsvConfig,
+    sort_config: &sorter::SortConfig,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    match (query.projection.as_slice(), &query.selection) {
+        // Handle "SELECT ... FROM <file> GROUP BY <columns> HAVING <condition>"
+        _ if !query.group_by.is_empty() || query.having.is_some() => {
+            handle_complex_query(query, csv_config, sort_config, out)?
+        }
+        // Handle "SELECT COUNT(*) FROM <file> WHERE <condition>"
+        ([item], Some(condition)) if item.is_count_star() && !query.is_limited() => {
+            let count = count_with_condition(&query.from, condition, csv_config)?;
+            writeln!(out, "COUNT(*)")?;
+            writeln!(out, "{}", count)?;
+        }
+        // Handle "SELECT COUNT(*) FROM <file>"
+        ([item], None) if item.is_count_star() && !query.is_limited() => {
+            let count = count_star(&query.from, csv_config)?;
+            writeln!(out, "COUNT(*)")?;
+            writeln!(out, "{}", count)?;
+        }
+        // Handle "SELECT * FROM <file>"
+        ([SelectItem::Wildcard], None)
+            if query.order_by.is_empty() && !query.is_limited() && !query.distinct =>
+        {
+            return select_star(&query.from, csv_config, out);
+        }
+        // Handle "SELECT * FROM <file> [WHERE <condition>] [ORDER BY <keys>] [LIMIT <n>]"
+        ([SelectItem::Wildcard], _) => {
+            handle_select_star_with_condition(query, csv_config, sort_config, out)?;
+        }
+        // Handle other queries
+        _ => handle_complex_query(query, csv_config, sort_config, out)?,
+    }
+    Ok(())
+}
+
+// Compiles the WHERE clause of a query, if it has one
+fn compile_selection(
+    query: &sql_parser::Query,
+    headers: &[String],
+) -> Result<Option<condition_checker::Predicate>, Box<dyn Error>> {
+    Ok(query
+        .selection
+        .as_ref()
+        .map(|condition| condition_checker::Predicate::compile(condition, headers))
+        .transpose()?)
+}
+
+/// Counts the number of rows in the CSV file (excluding the header row).
+fn count_star(
+    file_path: &str,
+    csv_config: &csv_reader::CsvConfig,
+) -> Result<usize, Box<dyn Error>> {
+    let csv_reader = csv_reader::CsvReader::new(file_path, csv_config)?;
+
+    // Quoted fields may contain newlines, so such files are counted record by record
+    if csv_reader.has_quotes() {
+        let mut count = 0;
+        for result in csv_reader.records() {
+            result?;
+            count += 1;
+        }
+        return Ok(count); // The header is already skipped
+    }
+    let total_lines = csv_reader::count_unquoted_records(csv_reader.data());
+
+    if csv_config.has_header {
+        Ok(total_lines.saturating_sub(1)) // Exclude the header
+    } else {
+        Ok(total_lines)
+    }
+}
+
+/// Counts rows in the CSV file that satisfy a given condition.
+fn count_with_condition(
+    file_path: &str,
+    condition: &Expr,
+    csv_config: &csv_reader::CsvConfig,
+) -> Result<usize, Box<dyn Error>> {
+    let csv_reader = csv_reader::CsvReader::new(file_path, csv_config)?;
+    let mut count = 0;
+
+    let headers = csv_reader.headers()?;
+    let record_iter = csv_reader.records();
+
+    // Compile the condition once; only the fields it references are parsed for each record
+    let predicate = condition_checker::Predicate::compile(condition, &headers)?;
+
+    // Process and count records matching the condition
+    for result in record_iter {
+        let record = result?;
+        if predicate.matches(&record) {
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Outputs the entire CSV file content to `out`.
+fn select_star(
+    file_path: &str,
+    csv_config: &csv_reader::CsvConfig,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let mmap = csv_reader::map_file(file_path)?; // Memory-map the file
+
+    // Files without a header get a line with the column names first
+    if !csv_config.has_header {
+        let csv_reader = csv_reader::CsvReader::new(file_path, csv_config)?;
+        write_header(out, &csv_reader.headers()?, csv_reader.dialect())?;
+    }
+
+    out.write_all(&mmap)?; // Write directly to `out`
+    out.flush()?; // Ensure all data is written
+    Ok(())
+}
+
+/// Handles queries like "SELECT * FROM <file> WHERE <condition> ORDER BY <keys> LIMIT <n>".
+fn handle_select_star_with_condition(
+    query: &sql_parser::Query,
+    csv_config: &csv_reader::CsvConfig,
+    sort_config: &sorter::SortConfig,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    // Create a CsvReader for the given file path
+    let csv_reader = csv_reader::CsvReader::new(&query.from, csv_config)?;
+    let dialect = *csv_reader.dialect();
+    let headers = csv_reader.headers()?;
+    let record_iter = csv_reader.records();
+
+    let predicate = compile_selection(query, &headers)?;
+    let (sort_keys, sort_orders) = order_by_keys(&query.order_by, &headers)?;
+    let mut row_limit = sorter::RowLimit::new(query.offset, query.limit);
+    let mut sorted_rows = sorter::Sorter::new(sort_orders.clone(), sort_config.clone(), row_limit);
+    let mut seen_lines = query.distinct.then(HashSet::new);
+
+    let mut writer = io::BufWriter::new(out);
+    write_header(&mut writer, &headers, &dialect)?;
+
+    // Process each record (line) in the CSV file
+    for result in record_iter {
+        // Without ORDER BY the scan can stop as soon as the LIMIT is reached
+        if row_limit.is_done() {
+            break;
+        }
+
+        // Get the next line from the iterator
+        let record = result?;
+
+        // Check if the record matches the condition
+        if predicate.as_ref().is_some_and(|p| !p.matches(&record)) {
+            continue;
+        }
+
+        if !is_new_line(&mut seen_lines, record.raw()) {
+            continue;
+        }
+
+        if sort_orders.is_empty() {
+            // Print the entire record as it appears in the file
+            if row_limit.admit() {
+                writer.write_all(record.raw())?;
+                writer.write_all(b"\n")?;
+            }
+        } else {
+            sorted_rows.push(sorter::Row {
+                keys: extract_keys(&record, &sort_keys),
+                line: record.raw().to_vec(),
+            })?;
+        }
+    }
+
+    sorted_rows.finish(&mut writer)?;
+    writer.flush()?; // Ensure all output is written to `out`
+
+    Ok(())
+}
+
+/// Compiles the ORDER BY keys of a query against the headers of the file.
+fn order_by_keys(
+    order_by: &[sql_parser::OrderByItem],
+    headers: &[String],
+) -> Result<(Vec<Operand>, Vec<sorter::SortOrder>), Box<dyn Error>> {
+    let mut keys = Vec::new();
+    let mut orders = Vec::new();
+    for item in order_by {
+        keys.push(Operand::compile(&item.expr, headers, "ORDER BY")?);
+        orders.push(sorter::SortOrder::new(item.ascending, item.nulls_first));
+    }
+    Ok((keys, orders))
+}
+
+// Extracts the sort keys of a record, with NULLs as empty strings
+fn extract_keys(record: &Record, keys: &[Operand]) -> Vec<String> {
+    keys.iter()
+        .map(|key| match key {
+            Operand::Column(index) => record.field_or_null(*index).unwrap_or("").to_string(),
+            _ => key.value(record).to_string(),
+        })
+        .collect()
+}
+
+// Writes fields separated by the delimiter, quoting fields that would otherwise be misread
+fn write_fields(
+    writer: &mut (impl Write + ?Sized),
+    fields: &[&[u8]],
+    dialect: &csv_reader::Dialect,
+) -> io::Result<()> {
+    let quote = [dialect.quote];
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            writer.write_all(&[dialect.delimiter])?;
+        }
+        if field
+            .iter()
+            .any(|&b| b == dialect.delimiter || b == dialect.quote || b == b'\n' || b == b'\r')
+        {
+            // Quotes inside the field are escaped, or doubled if there is no escape character
+            let escape = [dialect.escape.unwrap_or(dialect.quote)];
+            writer.write_all(&quote)?;
+            for part in field.split_inclusive(|&b| b == dialect.quote) {
+                match part.split_last() {
+                    Some((&last, rest)) if last == dialect.quote => {
+                        writer.write_all(rest)?;
+                        writer.write_all(&escape)?;
+                        writer.write_all(&quote)?;
+                    }
+                    _ => writer.write_all(part)?,
+                }
+            }
+            writer.write_all(&quote)?;
+        } else {
+            writer.write_all(field)?;
+        }
+    }
+    Ok(())
+}
+
+// Writes the column names of the output as the first line
+fn write_header(
+    writer: &mut (impl Write + ?Sized),
+    names: &[impl AsRef<str>],
+    dialect: &csv_reader::Dialect,
+) -> io::Result<()> {
+    let names: Vec<&[u8]> = names.iter().map(|name| name.as_ref().as_bytes()).collect();
+    write_fields(writer, &names, dialect)?;
+    writer.write_all(b"\n")
+}
+
+// Returns false if SELECT DISTINCT has already output the line
+fn is_new_line(seen_lines: &mut Option<HashSet<Vec<u8>>>, line: &[u8]) -> bool {
+    match seen_lines {
+        Some(seen_lines) => !seen_lines.contains(line) && seen_lines.insert(line.to_vec()),
+        None => true,
+    }
+}
+
+/// Handles more complex queries with aggregate functions or column selections.
+// #[inline(never)]
+fn handle_complex_query(
+    query: &sql_parser::Query,
+    csv_config: &csv_reader::CsvConfig,
+    sort_config: &sorter::SortConfig,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let mut csv_reader = csv_reader::CsvReader::new(&query.from, csv_config)?;
+    let is_aggregate_query = !query.group_by.is_empty()
+        || query.having.is_some()
+        || query.projection.iter().any(|item| {
+            let mut functions = Vec::new();
+            if let SelectItem::Expr { expr, .. } = item {
+                expr.aggregates(&mut functions);
+            }
+            !functions.is_empty()
+        });
+
+    if is_aggregate_query {
+        handle_aggregate_query(query, &mut csv_reader, sort_config, out)?;
+    } else {
+        handle_column_selection_query(query, &mut csv_reader, sort_config, out)?;
+    }
+
+    Ok(())
+}
+
+// An aggregate function call of a query, with its argument compiled against the headers
+struct AggregateCall<'a> {
+    label: String,
+    name: &'a str,
+    parameters: &'a [Expr],
+    distinct: bool,
+    argument: Option<Operand>, // None for COUNT(*)
+}
+
+// A group key with the aggregates of the group
+type Group = (Vec<String>, aggregates::Aggregates);
+
+/// Handles queries with aggregate functions (e.g., SUM, AVG, MIN), optionally grouped
+/// by one or more columns.
+// #[inline(never)]
+fn handle_aggregate_query(
+    query: &sql_parser::Query,
+    csv_reader: &mut csv_reader::CsvReader,
+    sort_config: &sorter::SortConfig,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let dialect = *csv_reader.dialect();
+    let headers = csv_reader.headers()?;
+
+    // Create a map from column names to their indices for quick lookup
+    let column_indices: std::collections::HashMap<_, _> = headers
+        .iter()
+        .enumerate()
+        .map(|(i, h)| (h.clone(), i))
+        .collect();
+    let column_index = |column_name: &str| -> Result<usize, Box<dyn Error>> {
+        column_indices
+            .get(column_name)
+            .copied()
+            .ok_or_else(|| format!("Unknown column: {}", column_name).into())
+    };
+
+    // Resolve the GROUP BY columns
+    let mut group_columns = Vec::new();
+    let mut group_indices = Vec::new();
+    for expr in &query.group_by {
+        match expr {
+            Expr::Column(column_name) => {
+                group_indices.push(column_index(column_name)?);
+                group_columns.push(column_name.clone());
+            }
+            _ => return Err(format!("Unsupported GROUP BY expression: {}", expr).into()),
+        }
+    }
+
+    // Output labels of the select list
+    let mut labels = Vec::new();
+    let mut functions = Vec::new();
+    for item in &query.projection {
+        match item {
+            SelectItem::Expr { expr, .. } => {
+                labels.push(item.label());
+                expr.aggregates(&mut functions);
+            }
+            SelectItem::Wildcard => return Err("Cannot mix * with aggregate functions".into()),
+        }
+    }
+    // HAVING and ORDER BY may reference aggregates that are not part of the select list
+    if let Some(having) = &query.having {
+        having.aggregates(&mut functions);
+    }
+    for item in &query.order_by {
+        item.expr.aggregates(&mut functions);
+    }
+    // Collect the aggregate functions along with the expression each one reads.
+    // COUNT(*) has no argument and counts every record.
+    let mut arguments: Vec<AggregateCall> = Vec::new();
+    for function in functions {
+        let label = function.to_string();
+        if let Expr::Function {
+            name,
+            args,
+            distinct,
+        } = function
+        {
+            if arguments.iter().any(|call| call.label == label) {
+                continue;
+            }
+            let (argument, parameters) = match args.as_slice() {
+                [Expr::Wildcard] if name == "COUNT" && !distinct => (None, &[][..]),
+                [argument, parameters @ ..] => (
+                    Some(Operand::compile(argument, &headers, &label)?),
+                    parameters,
+                ),
+                _ => return Err(format!("Unsupported arguments for {}", label).into()),
+            };
+            // Fail early on unknown functions and invalid parameters
+            aggregates::functions()
+                .read()
+                .unwrap()
+                .create(name, parameters, *distinct)?;
+            arguments.push(AggregateCall {
+                label,
+                name,
+                parameters,
+                distinct: *distinct,
+                argument,
+            });
+        }
+    }
+    let aggregate_labels: Vec<String> = arguments.iter().map(|call| call.label.clone()).collect();
+
+    // HAVING, the select list and ORDER BY read each group as a row of its key fields
+    // followed by its aggregate results
+    let group_scope = |clause| condition_checker::Scope {
+        columns: &group_columns,
+        aggregates: &aggregate_labels,
+        clause,
+    };
+    let having = query
+        .having
+        .as_ref()
+        .map(|having| Predicate::compile_in(having, &group_scope("HAVING")))
+        .transpose()?;
+    let outputs = query
+        .projection
+        .iter()
+        .filter_map(|item| match item {
+            SelectItem::Expr { expr, .. } => Some(expr),
+            SelectItem::Wildcard => None,
+        })
+        .map(|expr| Operand::compile_in(expr, &group_scope("SELECT")))
+        .collect::<Result<Vec<_>, _>>()?;
+    let order_keys = query
+        .order_by
+        .iter()
+        .map(|item| Operand::compile_in(&item.expr, &group_scope("ORDER BY")))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Builds the set of aggregates for a newly seen group
+    let new_group = || -> aggregates::Aggregates {
+        let functions = aggregates::functions().read().unwrap();
+        let mut aggregates = aggregates::Aggregates::new();
+        for call in &arguments {
+            let aggregate = functions
+                .create(call.name, call.parameters, call.distinct)
+                .unwrap();
+            aggregates.add_function(call.label.clone(), aggregate);
+        }
+        aggregates
+    };
+
+    let predicate = compile_selection(query, &headers)?;
+
+    // Aggregates the records of one chunk of the file into groups in first-seen order.
+    // Without GROUP BY there is exactly one group with an empty key, even for empty input.
+    let scan = |records: csv_reader::RecordIterator| -> io::Result<Vec<Group>> {
+        let mut groups: Vec<Group> = Vec::new();
+        let mut group_positions: HashMap<String, usize> = HashMap::new();
+        if group_indices.is_empty() {
+            groups.push((Vec::new(), new_group()));
+            group_positions.insert(String::new(), 0);
+        }
+        let mut key_buffer = String::new();
+
+        for result in records {
+            let record = result?;
+
+            if predicate.as_ref().is_some_and(|p| !p.matches(&record)) {
+                continue;
+            }
+
+            // Encode the group key into a reusable buffer; fields are separated by a control
+            // character so that ("a,b", "c") and ("a", "b,c") stay distinct. NULLs are all
+            // grouped together as empty fields.
+            key_buffer.clear();
+            for &index in &group_indices {
+                key_buffer.push_str(record.field_or_null(index).unwrap_or(""));
+                key_buffer.push('\u{1f}');
+            }
+            let position = match group_positions.get(key_buffer.as_str()) {
+                Some(&position) => position,
+                None => {
+                    let key = group_indices
+                        .iter()
+                        .map(|&index| record.field_or_null(index).unwrap_or("").to_string())
+                        .collect();
+                    groups.push((key, new_group()));
+                    group_positions.insert(key_buffer.clone(), groups.len() - 1);
+                    groups.len() - 1
+                }
+            };
+            let aggregates = &mut groups[position].1;
+
+            for call in &arguments {
+                let agg = aggregates.functions.get_mut(&call.label).unwrap();
+                match &call.argument {
+                    Some(Operand::Column(index)) => match record.field_or_null(*index) {
+                        Some(field) => agg.apply_raw(field),
+                        None => agg.apply(&Value::Null),
+                    },
+                    Some(operand) => agg.apply(&operand.value(&record)),
+                    None => agg.apply(&Value::Int64(1)),
+                }
+            }
+        }
+        Ok(groups)
+    };
+
+    // Large files without quotes are scanned in parallel, one chunk per thread
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let partials: Vec<io::Result<Vec<Group>>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = csv_reader
+            .record_chunks(threads)
+            .into_iter()
+            .map(|records| scope.spawn(|| scan(records)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|err| std::panic::resume_unwind(err))
+            })
+            .collect()
+    });
+
+    // Combine the partial groups in file order, so that the groups keep their first-seen
+    // order and order-sensitive aggregates such as FIRST see the same rows as a serial scan
+    let mut groups: Vec<Group> = Vec::new();
+    let mut group_positions: HashMap<Vec<String>, usize> = HashMap::new();
+    for partial in partials {
+        for (key, aggregates) in partial? {
+            match group_positions.get(&key) {
+                Some(&position) => groups[position].1.merge(&aggregates)?,
+                None => {
+                    group_positions.insert(key.clone(), groups.len());
+                    groups.push((key, aggregates));
+                }
+            }
+        }
+    }
+
+    // Formats a select list or ORDER BY expression for one group
+    let output_value = |operand: &Operand, row: &GroupRow| {
+        operand.text(row).map_or(String::new(), Cow::into_owned)
+    };
+    let sort_orders: Vec<sorter::SortOrder> = query
+        .order_by
+        .iter()
+        .map(|item| sorter::SortOrder::new(item.ascending, item.nulls_first))
+        .collect();
+    let mut row_limit = sorter::RowLimit::new(query.offset, query.limit);
+    let mut sorted_rows = sorter::Sorter::new(sort_orders.clone(), sort_config.clone(), row_limit);
+    let mut seen_lines = query.distinct.then(HashSet::new);
+
+    // Output one row of aggregate results per group that satisfies the HAVING clause
+    let mut writer = io::BufWriter::new(out);
+    write_header(&mut writer, &labels, &dialect)?;
+
+    for (key, aggregates) in &groups {
+        if row_limit.is_done() {
+            break;
+        }
+        let results = aggregates.results(&aggregate_labels);
+        let row = GroupRow {
+            key,
+            results: &results,
+        };
+        if having.as_ref().is_some_and(|having| !having.matches(&row)) {
+            continue;
+        }
+        let values: Vec<String> = outputs
+            .iter()
+            .map(|operand| output_value(operand, &row))
+            .collect();
+        let fields: Vec<&[u8]> = values.iter().map(|value| value.as_bytes()).collect();
+        let mut line = Vec::new();
+        write_fields(&mut line, &fields, &dialect)?;
+        if !is_new_line(&mut seen_lines, &line) {
+            continue;
+        }
+
+        if sort_orders.is_empty() {
+            if row_limit.admit() {
+                writer.write_all(&line)?;
+                writer.write_all(b"\n")?;
+            }
+        } else {
+            let keys = order_keys
+                .iter()
+                .map(|operand| output_value(operand, &row))
+                .collect();
+            sorted_rows.push(sorter::Row { keys, line })?;
+        }
+    }
+
+    sorted_rows.finish(&mut writer)?;
+    writer.flush()?; // Ensure all output is written to `out`
+
+    Ok(())
+}
+
+/// Handles column selection queries (e.g., "SELECT col1, col2").
+// #[inline(never)]
+fn handle_column_selection_query(
+    query: &sql_parser::Query,
+    csv_reader: &mut csv_reader::CsvReader,
+    sort_config: &sorter::SortConfig,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let dialect = *csv_reader.dialect();
+    let headers = csv_reader.headers()?;
+    let record_iter = csv_reader.records();
+
+    let mut labels = Vec::new();
+    let mut operands = Vec::new();
+    for item in &query.projection {
+        match item {
+            SelectItem::Expr { expr, .. } => {
+                labels.push(item.label());
+                operands.push(Operand::compile(expr, &headers, "SELECT")?);
+            }
+            SelectItem::Wildcard => return Err("Cannot mix * with other columns".into()),
+        }
+    }
+    let (sort_keys, sort_orders) = order_by_keys(&query.order_by, &headers)?;
+
+    // Prepare the buffered writer for faster output
+    let mut writer = io::BufWriter::new(out);
+
+    // Print the select list as the header
+    write_header(&mut writer, &labels, &dialect)?;
+
+    let mut line_buffer = Vec::new();
+    let mut seen_lines = query.distinct.then(HashSet::new);
+
+    let predicate = compile_selection(query, &headers)?;
+    let mut row_limit = sorter::RowLimit::new(query.offset, query.limit);
+    let mut sorted_rows = sorter::Sorter::new(sort_orders.clone(), sort_config.clone(), row_limit);
+
+    for result in record_iter {
+        // Without ORDER BY the scan can stop as soon as the LIMIT is reached
+        if row_limit.is_done() {
+            break;
+        }
+
+        let record = result?;
+
+        if predicate.as_ref().is_some_and(|p| !p.matches(&record)) {
+            continue;
+        }
+
+        // Columns are output as written, and computed values in their canonical form
+        let values: Vec<Cow<str>> = operands
+            .iter()
+            .map(|operand| match operand {
+                Operand::Column(index) => Cow::Borrowed(record.field(*index)),
+                _ => Cow::Owned(operand.value(&record).to_string()),
+            })
+            .collect();
+        let selected_fields: Vec<&[u8]> = values.iter().map(|value| value.as_bytes()).collect();
+
+        line_buffer.clear();
+        write_fields(&mut line_buffer, &selected_fields, &dialect)?;
+
+        if !is_new_line(&mut seen_lines, &line_buffer) {
+            continue;
+        }
+
+        if sort_orders.is_empty() {
+            // Write the selected fields directly to the writer
+            if row_limit.admit() {
+                writer.write_all(&line_buffer)?;
+                writer.write_all(b"\n")?;
+            }
+        } else {
+            // Buffer the line until all records have been read
+            sorted_rows.push(sorter::Row {
+                keys: extract_keys(&record, &sort_keys),
+                line: line_buffer.clone(),
+            })?;
+        }
+    }
+
+    sorted_rows.finish(&mut writer)?;
+    writer.flush()?; // Ensure all output is written to `out`
+
+    Ok(())
+}
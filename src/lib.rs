@@ -0,0 +1,21 @@
+//! SQL queries over CSV files.
+//!
+//! The `csvsql_v2_benchmark` binary is a thin command-line wrapper around this library.
+//! Custom aggregate functions can be added to the registry returned by `functions` before
+//! running a query, and are then parsed, validated and executed like the built-in ones.
+
+mod aggregates;
+mod condition_checker;
+mod csv_reader;
+mod executor;
+mod sketches;
+mod sorter;
+mod sql_parser;
+mod value;
+
+pub use aggregates::{functions, Aggregate, FunctionRegistry};
+pub use csv_reader::CsvConfig;
+pub use executor::execute;
+pub use sorter::SortConfig;
+pub use sql_parser::{parse_query, Expr, ParseError, Query};
+pub use value::Value;
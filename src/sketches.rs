@@ -0,0 +1,390 @@
+// Fixed-size summaries of large data streams, used by the approximate aggregates
+
+// HyperLogLog uses 2^PRECISION registers of one byte each
+const PRECISION: u32 = 14;
+const REGISTERS: usize = 1 << PRECISION;
+const MAX_RANK: u8 = 64 - PRECISION as u8 + 1;
+// A sparse entry takes 4 bytes, so beyond this many the dense array is smaller
+const MAX_SPARSE_REGISTERS: usize = REGISTERS / 4;
+
+// Default accuracy parameter of the KLL sketch, and the smallest capacity of a level
+pub const KLL_K: usize = 200;
+const KLL_MIN_WIDTH: usize = 8;
+const KLL_SEED: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// Estimates the number of distinct values in a stream.
+///
+/// Small streams keep only the registers that are set, and switch to a dense array of
+/// 16 KiB once that would be smaller. The standard error of the estimate is
+/// 1.04 / sqrt(2^14), about 0.8%, so estimates are within 2.4% of the true count with 99%
+/// confidence. Small counts are corrected with linear counting and are close to exact.
+#[derive(Debug, Clone)]
+pub struct HyperLogLog {
+    registers: Registers,
+}
+
+// Highest rank seen per register
+#[derive(Debug, Clone)]
+enum Registers {
+    Sparse(Vec<(u16, u8)>), // Registers that are set, sorted by index
+    Dense(Vec<u8>),
+}
+
+impl HyperLogLog {
+    pub fn new() -> Self {
+        HyperLogLog {
+            registers: Registers::Sparse(Vec::new()),
+        }
+    }
+
+    /// Restores a sketch from the pairs of index and rank returned by `ranks`, or returns
+    /// None if a register does not exist.
+    pub fn from_ranks(ranks: &[(u16, u8)]) -> Option<Self> {
+        let mut sketch = HyperLogLog::new();
+        for &(index, rank) in ranks {
+            if index as usize >= REGISTERS || rank > MAX_RANK {
+                return None;
+            }
+            sketch.set(index, rank);
+        }
+        Some(sketch)
+    }
+
+    /// The registers that are set, as pairs of index and rank.
+    pub fn ranks(&self) -> Vec<(u16, u8)> {
+        match &self.registers {
+            Registers::Sparse(entries) => entries.clone(),
+            Registers::Dense(registers) => (0..)
+                .zip(registers.iter().copied())
+                .filter(|&(_, rank)| rank > 0)
+                .collect(),
+        }
+    }
+
+    pub fn insert(&mut self, value: &[u8]) {
+        let hash = hash64(value);
+        let index = (hash >> (64 - PRECISION)) as u16;
+        // Position of the first set bit among the remaining bits, capped when all are zero
+        let rank = ((hash << PRECISION).leading_zeros() + 1).min(MAX_RANK as u32) as u8;
+        self.set(index, rank);
+    }
+
+    /// Adds the values of another sketch; the result is the sketch of both streams.
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (index, rank) in other.ranks() {
+            self.set(index, rank);
+        }
+    }
+
+    pub fn estimate(&self) -> f64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let ranks = self.ranks();
+        // Registers that are not set each add 2^0 to the sum
+        let zeros = REGISTERS - ranks.len();
+        let sum: f64 = zeros as f64
+            + ranks
+                .iter()
+                .map(|&(_, rank)| 2f64.powi(-(rank as i32)))
+                .sum::<f64>();
+        let estimate = alpha * m * m / sum;
+
+        if estimate <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            estimate
+        }
+    }
+
+    // Raises a register to `rank`, switching to the dense array when the sparse list
+    // outgrows it
+    fn set(&mut self, index: u16, rank: u8) {
+        match &mut self.registers {
+            Registers::Dense(registers) => {
+                let register = &mut registers[index as usize];
+                *register = (*register).max(rank);
+            }
+            Registers::Sparse(entries) => {
+                match entries.binary_search_by_key(&index, |&(index, _)| index) {
+                    Ok(i) => entries[i].1 = entries[i].1.max(rank),
+                    Err(i) => entries.insert(i, (index, rank)),
+                }
+                if entries.len() > MAX_SPARSE_REGISTERS {
+                    let mut registers = vec![0; REGISTERS];
+                    for &(index, rank) in entries.iter() {
+                        registers[index as usize] = rank;
+                    }
+                    self.registers = Registers::Dense(registers);
+                }
+            }
+        }
+    }
+}
+
+/// Estimates quantiles of a stream of numbers with a KLL sketch.
+///
+/// Values are kept in levels of compactors, where each value at level h stands for 2^h
+/// values of the input. When the sketch exceeds its capacity, a level is sorted and every
+/// other value is promoted to the next level. With k = 200 the sketch keeps fewer than
+/// 700 values, and the rank of a returned quantile is within about 1.7% of the requested
+/// rank with 99% confidence. Compactions choose their half pseudo-randomly from a fixed
+/// seed, so results are reproducible.
+#[derive(Debug, Clone)]
+pub struct Kll {
+    k: usize,
+    levels: Vec<Vec<f64>>,
+    count: u64,
+    random: u64, // Xorshift state
+}
+
+impl Kll {
+    pub fn new(k: usize) -> Self {
+        Kll {
+            k,
+            levels: vec![Vec::new()],
+            count: 0,
+            random: KLL_SEED,
+        }
+    }
+
+    /// Restores a sketch from its levels, where level h holds values of weight 2^h.
+    pub fn from_levels(k: usize, mut levels: Vec<Vec<f64>>) -> Self {
+        if levels.is_empty() {
+            levels.push(Vec::new());
+        }
+        let count = levels
+            .iter()
+            .enumerate()
+            .map(|(level, values)| (values.len() as u64) << level)
+            .sum();
+        let mut sketch = Kll {
+            k,
+            levels,
+            count,
+            random: KLL_SEED,
+        };
+        sketch.compress();
+        sketch
+    }
+
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    pub fn levels(&self) -> &[Vec<f64>] {
+        &self.levels
+    }
+
+    pub fn insert(&mut self, value: f64) {
+        self.levels[0].push(value);
+        self.count += 1;
+        self.compress();
+    }
+
+    /// Adds the values of another sketch by combining their levels and compacting until
+    /// the result fits again.
+    pub fn merge(&mut self, other: &Kll) {
+        if other.levels.len() > self.levels.len() {
+            self.levels.resize(other.levels.len(), Vec::new());
+        }
+        for (level, values) in self.levels.iter_mut().zip(&other.levels) {
+            level.extend_from_slice(values);
+        }
+        self.count += other.count;
+        self.compress();
+    }
+
+    /// Returns the value whose rank is closest to `fraction` of the values inserted, or None
+    /// if the sketch is empty.
+    pub fn quantile(&self, fraction: f64) -> Option<f64> {
+        let mut weighted: Vec<(f64, u64)> = self
+            .levels
+            .iter()
+            .enumerate()
+            .flat_map(|(level, values)| values.iter().map(move |&value| (value, 1 << level)))
+            .collect();
+        weighted.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+
+        let target = (fraction * self.count as f64).ceil().max(1.0) as u64;
+        let mut rank = 0;
+        for &(value, weight) in &weighted {
+            rank += weight;
+            if rank >= target {
+                return Some(value);
+            }
+        }
+        weighted.last().map(|&(value, _)| value)
+    }
+
+    // Levels shrink geometrically below the top one, which holds up to k values
+    fn capacity(&self, level: usize) -> usize {
+        let depth = self.levels.len() - level - 1;
+        ((self.k as f64 * (2.0f64 / 3.0).powi(depth as i32)).ceil() as usize).max(KLL_MIN_WIDTH)
+    }
+
+    // Compacts the lowest full level until the sketch fits its capacity
+    fn compress(&mut self) {
+        loop {
+            let size: usize = self.levels.iter().map(Vec::len).sum();
+            let capacity: usize = (0..self.levels.len()).map(|l| self.capacity(l)).sum();
+            if size < capacity {
+                return;
+            }
+            let level = (0..self.levels.len())
+                .find(|&l| self.levels[l].len() >= self.capacity(l))
+                .unwrap_or(0);
+            self.compact(level);
+        }
+    }
+
+    // Promotes every other value of a sorted level to the next one; an odd value out stays
+    fn compact(&mut self, level: usize) {
+        if level + 1 == self.levels.len() {
+            self.levels.push(Vec::new());
+        }
+        let mut values = std::mem::take(&mut self.levels[level]);
+        values.sort_unstable_by(f64::total_cmp);
+        if values.len() % 2 == 1 {
+            self.levels[level].push(values.pop().unwrap());
+        }
+        let offset = self.next_bit();
+        let promoted = values.into_iter().skip(offset).step_by(2);
+        self.levels[level + 1].extend(promoted);
+    }
+
+    fn next_bit(&mut self) -> usize {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        (self.random & 1) as usize
+    }
+}
+
+// 64-bit FNV-1a followed by the MurmurHash3 finaliser, which spreads FNV's weak high bits
+fn hash64(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hll(values: std::ops::Range<u64>) -> HyperLogLog {
+        let mut sketch = HyperLogLog::new();
+        for value in values {
+            sketch.insert(value.to_string().as_bytes());
+        }
+        sketch
+    }
+
+    fn relative_error(estimate: f64, count: u64) -> f64 {
+        (estimate - count as f64).abs() / count as f64
+    }
+
+    #[test]
+    fn hyperloglog_stays_within_documented_error() {
+        for count in [10_000, 100_000, 1_000_000] {
+            let estimate = hll(0..count).estimate();
+            assert!(
+                relative_error(estimate, count) < 0.024,
+                "{} estimated as {}",
+                count,
+                estimate
+            );
+        }
+        // Linear counting keeps small counts close to exact
+        for count in [1, 10, 100, 1000] {
+            let estimate = hll(0..count).estimate();
+            assert!(
+                (estimate - count as f64).abs() <= 0.024 * count as f64 + 1.0,
+                "{} estimated as {}",
+                count,
+                estimate
+            );
+        }
+        assert_eq!(HyperLogLog::new().estimate(), 0.0);
+    }
+
+    #[test]
+    fn hyperloglog_merge_matches_single_sketch() {
+        // Overlapping halves, one sparse and one dense
+        let mut merged = hll(0..3000);
+        merged.merge(&hll(1000..60_000));
+        let single = hll(0..60_000);
+        assert_eq!(merged.ranks(), single.ranks());
+        assert_eq!(merged.estimate(), single.estimate());
+
+        let restored = HyperLogLog::from_ranks(&single.ranks()).unwrap();
+        assert_eq!(restored.ranks(), single.ranks());
+        assert!(HyperLogLog::from_ranks(&[(REGISTERS as u16, 1)]).is_none());
+        assert!(HyperLogLog::from_ranks(&[(0, MAX_RANK + 1)]).is_none());
+    }
+
+    // Values 0..count in a scrambled order, so each value is one less than its rank
+    fn scrambled(count: u64) -> impl Iterator<Item = f64> {
+        (0..count).map(move |i| ((i * 7919) % count) as f64)
+    }
+
+    fn assert_rank_error(sketch: &Kll, count: u64) {
+        for percent in 1..100 {
+            let fraction = percent as f64 / 100.0;
+            let value = sketch.quantile(fraction).unwrap();
+            let rank = (value + 1.0) / count as f64;
+            assert!(
+                (rank - fraction).abs() < 0.017,
+                "quantile {} has rank {}",
+                fraction,
+                rank
+            );
+        }
+    }
+
+    #[test]
+    fn kll_stays_within_documented_error() {
+        let count = 1_000_000;
+        let mut sketch = Kll::new(KLL_K);
+        for value in scrambled(count) {
+            sketch.insert(value);
+        }
+        assert!(sketch.levels().iter().map(Vec::len).sum::<usize>() < 700);
+        assert_rank_error(&sketch, count);
+        assert_eq!(Kll::new(KLL_K).quantile(0.5), None);
+    }
+
+    #[test]
+    fn kll_merge_stays_within_documented_error() {
+        let count = 400_000;
+        let values: Vec<f64> = scrambled(count).collect();
+        let mut merged = Kll::new(KLL_K);
+        for chunk in values.chunks(70_000) {
+            let mut sketch = Kll::new(KLL_K);
+            for &value in chunk {
+                sketch.insert(value);
+            }
+            // Partial states cross threads as levels
+            merged.merge(&Kll::from_levels(sketch.k(), sketch.levels().to_vec()));
+        }
+        assert!(merged.levels().iter().map(Vec::len).sum::<usize>() < 700);
+        assert_rank_error(&merged, count);
+    }
+
+    #[test]
+    fn kll_is_exact_below_capacity() {
+        let mut sketch = Kll::new(KLL_K);
+        for value in scrambled(101) {
+            sketch.insert(value);
+        }
+        assert_eq!(sketch.quantile(0.0), Some(0.0));
+        assert_eq!(sketch.quantile(0.5), Some(50.0));
+        assert_eq!(sketch.quantile(1.0), Some(100.0));
+    }
+}
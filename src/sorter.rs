@@ -0,0 +1,806 @@
+use crate::value::Value;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Maximum number of runs merged at once, to stay well below file descriptor limits
+const MAX_MERGE_FAN_IN: usize = 64;
+// Rough per-row bookkeeping cost (vectors, typed keys) counted against the memory budget
+const ROW_OVERHEAD: usize = 64;
+const KEY_OVERHEAD: usize = 32;
+// Top-N keeps one heap per possible final typing of the keys, so it is only used for up
+// to this many sort keys
+const MAX_TOP_N_KEYS: usize = 4;
+
+/// Direction and NULL placement of a single ORDER BY key.
+#[derive(Debug, Clone, Copy)]
+pub struct SortOrder {
+    pub ascending: bool,
+    pub nulls_first: bool,
+}
+
+impl SortOrder {
+    pub fn new(ascending: bool, nulls_first: Option<bool>) -> Self {
+        // Like PostgreSQL, NULLs sort as if larger than every other value by default
+        SortOrder {
+            ascending,
+            nulls_first: nulls_first.unwrap_or(!ascending),
+        }
+    }
+}
+
+/// Memory budget and spill location used by ORDER BY.
+#[derive(Debug, Clone)]
+pub struct SortConfig {
+    pub memory_budget: usize, // Approximate bytes of buffered rows before a run is spilled
+    pub temp_dir: PathBuf,    // Directory in which the spill directory is created
+}
+
+/// OFFSET/LIMIT window over the output rows.
+#[derive(Debug, Clone, Copy)]
+pub struct RowLimit {
+    offset: usize,
+    limit: Option<usize>,
+    seen: usize, // Output rows counted so far, including skipped ones
+}
+
+impl RowLimit {
+    pub fn new(offset: usize, limit: Option<usize>) -> Self {
+        RowLimit {
+            offset,
+            limit,
+            seen: 0,
+        }
+    }
+
+    /// Number of leading rows needed to produce the window, if limited.
+    pub fn keep(&self) -> Option<usize> {
+        self.limit.map(|limit| self.offset.saturating_add(limit))
+    }
+
+    /// Returns true once no further rows can be output, so scanning can stop.
+    pub fn is_done(&self) -> bool {
+        self.keep().is_some_and(|keep| self.seen >= keep)
+    }
+
+    /// Counts an output row and returns whether it falls inside the window.
+    pub fn admit(&mut self) -> bool {
+        let inside = self.seen >= self.offset && !self.is_done();
+        self.seen += 1;
+        inside
+    }
+}
+
+/// A buffered output line together with the raw values of its sort keys.
+#[derive(Debug)]
+pub struct Row {
+    pub keys: Vec<String>,
+    pub line: Vec<u8>,
+}
+
+// A sort key value after the key's type has been decided
+#[derive(Debug, Clone)]
+enum SortValue {
+    Null,
+    Typed(Value), // A number, date or timestamp, or a boolean
+    Text(String),
+}
+
+// Values of the same kind can all be compared with `Value::compare`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KeyKind {
+    Number,
+    Time,
+    Bool,
+}
+
+impl KeyKind {
+    fn of(value: &Value) -> Option<KeyKind> {
+        match value {
+            Value::Int64(_) | Value::Float64(_) | Value::Decimal(..) => Some(KeyKind::Number),
+            Value::Date(_) | Value::Timestamp(_) => Some(KeyKind::Time),
+            Value::Bool(_) => Some(KeyKind::Bool),
+            Value::Null | Value::Text(_) => None,
+        }
+    }
+}
+
+/// External merge sort over output rows.
+///
+/// Rows are buffered until the memory budget is exceeded, at which point the buffer is
+/// sorted and written to a temporary file as a run. `finish` k-way merges the runs, so
+/// inputs much larger than the budget can be sorted. When everything fits in the
+/// budget no files are written at all.
+///
+/// With a LIMIT, only the rows that can still make it into the first OFFSET + LIMIT are
+/// kept, in bounded heaps (see `TopN`), and the scan never spills unless those rows
+/// alone exceed the budget.
+///
+/// Each key is typed like a field in a condition: if every non-empty value of the key is a
+/// number (or every one a date or timestamp, or a boolean), the values are compared with
+/// `Value::compare`, so integers and decimals stay exact. Otherwise the key is compared
+/// as text. Empty values are NULLs. The sort is stable, so rows
+/// with equal keys keep their input order.
+pub struct Sorter {
+    orders: Rc<[SortOrder]>,
+    config: SortConfig,
+    row_limit: RowLimit,
+    top_n: Option<TopN>,
+    buffer: Vec<Row>,
+    buffered_bytes: usize,
+    typed: Vec<bool>, // Whether each key has only held values of one kind so far
+    kinds: Vec<Option<KeyKind>>, // The kind of each typed key, once a value was seen
+    runs: Vec<Run>,
+    spill_dir: Option<SpillDir>,
+}
+
+/// Top-N selection for ORDER BY ... LIMIT.
+///
+/// Whether a key compares as numbers or text is only known once every row has been seen,
+/// so one bounded max-heap is kept per typing the keys may still end up with: heap `mask`
+/// treats key `k` as typed if bit `k` is set. When a key turns out to hold text, the
+/// heaps that assumed it typed are dropped. Rows are shared between heaps and freed
+/// once no heap holds them.
+struct TopN {
+    keep: usize,
+    heaps: Vec<Option<BinaryHeap<HeapEntry>>>, // Indexed by mask, None once ruled out
+    rows: Vec<Option<(Row, usize)>>,           // Row slots with their heap reference counts
+    free: Vec<usize>,
+    bytes: usize,
+    next_seq: u64,
+}
+
+// A row in a top-N heap; the largest entry is the worst row kept, later rows losing ties
+struct HeapEntry {
+    values: Vec<SortValue>,
+    seq: u64,
+    slot: usize,
+    orders: Rc<[SortOrder]>,
+}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_keys(&self.values, &other.values, &self.orders).then(self.seq.cmp(&other.seq))
+    }
+}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+impl TopN {
+    fn new(keep: usize, key_count: usize) -> Self {
+        TopN {
+            keep,
+            heaps: (0..1usize << key_count)
+                .map(|_| Some(BinaryHeap::with_capacity(keep.min(1024) + 1)))
+                .collect(),
+            rows: Vec::new(),
+            free: Vec::new(),
+            bytes: 0,
+            next_seq: 0,
+        }
+    }
+
+    // Drops the heaps that assumed `key` to be typed
+    fn rule_out_typed(&mut self, key: usize) {
+        for mask in 0..self.heaps.len() {
+            if mask & (1 << key) != 0 {
+                if let Some(heap) = self.heaps[mask].take() {
+                    for entry in heap {
+                        self.release(entry.slot);
+                    }
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, row: Row, orders: &Rc<[SortOrder]>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        // Find the heaps the row makes it into
+        let mut accepted = Vec::new();
+        for (mask, heap) in self.heaps.iter().enumerate() {
+            if let Some(heap) = heap {
+                let entry = HeapEntry {
+                    values: mask_values(&row.keys, mask),
+                    seq,
+                    slot: 0,
+                    orders: orders.clone(),
+                };
+                if heap.len() < self.keep || heap.peek().is_some_and(|worst| entry < *worst) {
+                    accepted.push((mask, entry));
+                }
+            }
+        }
+        if accepted.is_empty() {
+            return;
+        }
+
+        self.bytes += row_size(&row);
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            None => {
+                self.rows.push(None);
+                self.rows.len() - 1
+            }
+        };
+        self.rows[slot] = Some((row, accepted.len()));
+
+        for (mask, mut entry) in accepted {
+            entry.slot = slot;
+            let heap = self.heaps[mask].as_mut().unwrap();
+            heap.push(entry);
+            if heap.len() > self.keep {
+                let evicted = heap.pop().unwrap().slot;
+                self.release(evicted);
+            }
+        }
+    }
+
+    fn release(&mut self, slot: usize) {
+        if let Some((row, refs)) = &mut self.rows[slot] {
+            *refs -= 1;
+            if *refs == 0 {
+                self.bytes -= row_size(row);
+                self.rows[slot] = None;
+                self.free.push(slot);
+            }
+        }
+    }
+
+    // Takes the rows of the heap for the final key types, in sorted order
+    fn into_sorted(mut self, mask: usize) -> Vec<Row> {
+        let heap = self.heaps[mask].take().unwrap_or_default();
+        heap.into_sorted_vec()
+            .into_iter()
+            .filter_map(|entry| self.rows[entry.slot].take().map(|(row, _)| row))
+            .collect()
+    }
+
+    // Takes every row still held by some heap, in input order
+    fn into_rows(mut self) -> Vec<Row> {
+        let mut slots: Vec<(u64, usize)> = Vec::new();
+        for heap in self.heaps.iter_mut().flatten() {
+            slots.extend(heap.drain().map(|entry| (entry.seq, entry.slot)));
+        }
+        slots.sort_unstable();
+        slots.dedup();
+        slots
+            .into_iter()
+            .filter_map(|(_, slot)| self.rows[slot].take().map(|(row, _)| row))
+            .collect()
+    }
+}
+
+// Types the keys of a row as values where `mask` has the key's bit set
+fn mask_values(keys: &[String], mask: usize) -> Vec<SortValue> {
+    let typed: Vec<bool> = (0..keys.len()).map(|k| mask & (1 << k) != 0).collect();
+    sort_values(keys, &typed)
+}
+
+fn row_size(row: &Row) -> usize {
+    ROW_OVERHEAD
+        + row.line.len()
+        + row
+            .keys
+            .iter()
+            .map(|k| k.len() + KEY_OVERHEAD)
+            .sum::<usize>()
+}
+
+// A sorted run on disk, with the key types it was sorted by
+struct Run {
+    path: PathBuf,
+    typed: Vec<bool>,
+}
+
+// Temporary directory holding the runs, removed when the sorter is dropped
+struct SpillDir {
+    path: PathBuf,
+    next_id: usize,
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+impl Sorter {
+    pub fn new(orders: Vec<SortOrder>, config: SortConfig, row_limit: RowLimit) -> Self {
+        let typed = vec![true; orders.len()];
+        let top_n = row_limit
+            .keep()
+            .filter(|_| orders.len() <= MAX_TOP_N_KEYS)
+            .map(|keep| TopN::new(keep, orders.len()));
+        Sorter {
+            orders: orders.into(),
+            config,
+            row_limit,
+            top_n,
+            buffer: Vec::new(),
+            buffered_bytes: 0,
+            kinds: vec![None; typed.len()],
+            typed,
+            runs: Vec::new(),
+            spill_dir: None,
+        }
+    }
+
+    /// Adds a row, spilling a sorted run to disk if the memory budget is exceeded.
+    pub fn push(&mut self, row: Row) -> io::Result<()> {
+        for (k, key) in row.keys.iter().enumerate() {
+            if !self.typed[k] {
+                continue;
+            }
+            let value = Value::parse(key);
+            if value.is_null() {
+                continue;
+            }
+            match (KeyKind::of(&value), self.kinds[k]) {
+                (Some(kind), None) => self.kinds[k] = Some(kind),
+                (Some(kind), Some(seen)) if kind == seen => {}
+                _ => {
+                    self.typed[k] = false;
+                    if let Some(top_n) = &mut self.top_n {
+                        top_n.rule_out_typed(k);
+                    }
+                }
+            }
+        }
+
+        if let Some(top_n) = &mut self.top_n {
+            top_n.push(row, &self.orders);
+            if top_n.bytes <= self.config.memory_budget {
+                return Ok(());
+            }
+            // The rows that may still be needed no longer fit, fall back to a full sort
+            let top_n = self.top_n.take().unwrap();
+            self.buffered_bytes = top_n.bytes;
+            self.buffer = top_n.into_rows();
+        } else {
+            self.buffered_bytes += row_size(&row);
+            self.buffer.push(row);
+        }
+
+        if self.buffered_bytes > self.config.memory_budget {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the rows inside the OFFSET/LIMIT window in sorted order, one line each.
+    pub fn finish(mut self, writer: &mut impl Write) -> io::Result<()> {
+        let mut row_limit = self.row_limit;
+        if let Some(top_n) = self.top_n.take() {
+            let mask = (0..self.typed.len())
+                .filter(|&k| self.typed[k])
+                .fold(0, |mask, k| mask | (1 << k));
+            for row in top_n.into_sorted(mask) {
+                if row_limit.admit() {
+                    writer.write_all(&row.line)?;
+                    writer.write_all(b"\n")?;
+                }
+            }
+            return Ok(());
+        }
+
+        if self.runs.is_empty() {
+            let rows = std::mem::take(&mut self.buffer);
+            for row in sort_buffer(rows, &self.typed, &self.orders) {
+                if row_limit.is_done() {
+                    break;
+                }
+                if row_limit.admit() {
+                    writer.write_all(&row.line)?;
+                    writer.write_all(b"\n")?;
+                }
+            }
+            return Ok(());
+        }
+
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+
+        // Runs sorted before a key turned out to hold text are re-sorted with the final
+        // key types. Each run was written from a single buffer, so it fits the budget.
+        for run in &mut self.runs {
+            if run.typed != self.typed {
+                let rows = read_run(&run.path, self.orders.len())?;
+                let rows = sort_buffer(rows, &self.typed, &self.orders);
+                write_run(&run.path, &rows)?;
+                run.typed = self.typed.clone();
+            }
+        }
+
+        // Merge in passes until few enough runs are left for the final merge
+        let mut runs: Vec<PathBuf> = self.runs.drain(..).map(|run| run.path).collect();
+        while runs.len() > MAX_MERGE_FAN_IN {
+            let mut merged = Vec::new();
+            for chunk in runs.chunks(MAX_MERGE_FAN_IN) {
+                let path = self.next_run_path()?;
+                let mut out = BufWriter::new(File::create(&path)?);
+                merge_runs(chunk, &self.typed, &self.orders, |row| {
+                    write_row(&mut out, &row).map(|_| true)
+                })?;
+                out.flush()?;
+                for path in chunk {
+                    fs::remove_file(path)?;
+                }
+                merged.push(path);
+            }
+            runs = merged;
+        }
+
+        merge_runs(&runs, &self.typed, &self.orders, |row| {
+            if row_limit.admit() {
+                writer.write_all(&row.line)?;
+                writer.write_all(b"\n")?;
+            }
+            Ok(!row_limit.is_done())
+        })
+    }
+
+    // Sorts the buffer and writes it out as a new run
+    fn spill(&mut self) -> io::Result<()> {
+        let rows = std::mem::take(&mut self.buffer);
+        let rows = sort_buffer(rows, &self.typed, &self.orders);
+        let path = self.next_run_path()?;
+        write_run(&path, &rows)?;
+        self.runs.push(Run {
+            path,
+            typed: self.typed.clone(),
+        });
+        self.buffered_bytes = 0;
+        Ok(())
+    }
+
+    // Returns a fresh run file path, creating the spill directory on first use
+    fn next_run_path(&mut self) -> io::Result<PathBuf> {
+        if self.spill_dir.is_none() {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos());
+            let path =
+                self.config
+                    .temp_dir
+                    .join(format!("csvsql-sort-{}-{}", std::process::id(), nanos));
+            fs::create_dir_all(&path)?;
+            self.spill_dir = Some(SpillDir { path, next_id: 0 });
+        }
+
+        let spill_dir = self.spill_dir.as_mut().unwrap();
+        spill_dir.next_id += 1;
+        Ok(spill_dir
+            .path
+            .join(format!("run-{}.bin", spill_dir.next_id)))
+    }
+}
+
+fn sort_values(keys: &[String], typed: &[bool]) -> Vec<SortValue> {
+    keys.iter()
+        .zip(typed)
+        .map(|(value, &typed)| {
+            if value.trim().is_empty() {
+                SortValue::Null
+            } else if typed {
+                SortValue::Typed(Value::parse(value))
+            } else {
+                SortValue::Text(value.clone())
+            }
+        })
+        .collect()
+}
+
+fn sort_buffer(rows: Vec<Row>, typed: &[bool], orders: &[SortOrder]) -> Vec<Row> {
+    let mut keyed: Vec<(Vec<SortValue>, Row)> = rows
+        .into_iter()
+        .map(|row| (sort_values(&row.keys, typed), row))
+        .collect();
+    keyed.sort_by(|(a, _), (b, _)| compare_keys(a, b, orders));
+    keyed.into_iter().map(|(_, row)| row).collect()
+}
+
+fn compare_keys(a: &[SortValue], b: &[SortValue], orders: &[SortOrder]) -> Ordering {
+    for ((a, b), order) in a.iter().zip(b).zip(orders) {
+        let ordering = match (a, b) {
+            (SortValue::Null, SortValue::Null) => Ordering::Equal,
+            // NULL placement is independent of the sort direction
+            (SortValue::Null, _) if order.nulls_first => return Ordering::Less,
+            (SortValue::Null, _) => return Ordering::Greater,
+            (_, SortValue::Null) if order.nulls_first => return Ordering::Greater,
+            (_, SortValue::Null) => return Ordering::Less,
+            // NaN is the only value that does not compare; it sorts after all numbers
+            (SortValue::Typed(a), SortValue::Typed(b)) => {
+                a.compare(b).unwrap_or_else(|| is_nan(a).cmp(&is_nan(b)))
+            }
+            (SortValue::Text(a), SortValue::Text(b)) => a.cmp(b),
+            // Mixed types cannot happen since a key is either all typed or all text
+            _ => Ordering::Equal,
+        };
+        let ordering = if order.ascending {
+            ordering
+        } else {
+            ordering.reverse()
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+fn is_nan(value: &Value) -> bool {
+    matches!(value, Value::Float64(x) if x.is_nan())
+}
+
+// Next row of a run during a merge; ordered so that BinaryHeap pops the smallest row,
+// breaking ties by run so that the merge stays stable
+struct MergeEntry<'a> {
+    values: Vec<SortValue>,
+    row: Row,
+    run: usize,
+    orders: &'a [SortOrder],
+}
+
+impl Ord for MergeEntry<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_keys(&other.values, &self.values, self.orders).then(other.run.cmp(&self.run))
+    }
+}
+
+impl PartialOrd for MergeEntry<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for MergeEntry<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MergeEntry<'_> {}
+
+// K-way merges sorted runs, passing each row to `emit` in order until it returns false
+fn merge_runs(
+    paths: &[PathBuf],
+    typed: &[bool],
+    orders: &[SortOrder],
+    mut emit: impl FnMut(Row) -> io::Result<bool>,
+) -> io::Result<()> {
+    let mut readers = paths
+        .iter()
+        .map(|path| File::open(path).map(BufReader::new))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut heap = BinaryHeap::with_capacity(readers.len());
+    for (run, reader) in readers.iter_mut().enumerate() {
+        if let Some(row) = read_row(reader, orders.len())? {
+            let values = sort_values(&row.keys, typed);
+            heap.push(MergeEntry {
+                values,
+                row,
+                run,
+                orders,
+            });
+        }
+    }
+
+    while let Some(MergeEntry { row, run, .. }) = heap.pop() {
+        if !emit(row)? {
+            break;
+        }
+        if let Some(row) = read_row(&mut readers[run], orders.len())? {
+            let values = sort_values(&row.keys, typed);
+            heap.push(MergeEntry {
+                values,
+                row,
+                run,
+                orders,
+            });
+        }
+    }
+    Ok(())
+}
+
+// Runs store each row as length-prefixed keys followed by the length-prefixed line
+fn write_run(path: &Path, rows: &[Row]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for row in rows {
+        write_row(&mut writer, row)?;
+    }
+    writer.flush()
+}
+
+fn write_row(writer: &mut impl Write, row: &Row) -> io::Result<()> {
+    for key in &row.keys {
+        write_bytes(writer, key.as_bytes())?;
+    }
+    write_bytes(writer, &row.line)
+}
+
+fn write_bytes(writer: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+fn read_run(path: &Path, key_count: usize) -> io::Result<Vec<Row>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut rows = Vec::new();
+    while let Some(row) = read_row(&mut reader, key_count)? {
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+fn read_row(reader: &mut impl BufRead, key_count: usize) -> io::Result<Option<Row>> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let keys = (0..key_count)
+        .map(|_| {
+            let bytes = read_bytes(reader)?;
+            String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let line = read_bytes(reader)?;
+    Ok(Some(Row { keys, line }))
+}
+
+fn read_bytes(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 8];
+    reader.read_exact(&mut len)?;
+    let mut bytes = vec![0u8; u64::from_le_bytes(len) as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(memory_budget: usize) -> SortConfig {
+        SortConfig {
+            memory_budget,
+            temp_dir: std::env::temp_dir(),
+        }
+    }
+
+    // Sorts single-key rows whose lines are "key#position", returning the output lines
+    // and whether any run was spilled
+    fn sort(
+        keys: &[&str],
+        order: SortOrder,
+        memory_budget: usize,
+        limit: Option<usize>,
+    ) -> (Vec<String>, bool) {
+        let mut sorter = Sorter::new(vec![order], config(memory_budget), RowLimit::new(0, limit));
+        for (i, key) in keys.iter().enumerate() {
+            let row = Row {
+                keys: vec![key.to_string()],
+                line: format!("{}#{}", key, i).into_bytes(),
+            };
+            sorter.push(row).unwrap();
+        }
+        let spilled = !sorter.runs.is_empty();
+        let mut out = Vec::new();
+        sorter.finish(&mut out).unwrap();
+        let lines = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        (lines, spilled)
+    }
+
+    fn numbers(count: usize) -> Vec<String> {
+        (0..count)
+            .map(|i| ((i * 7919) % 1000).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn spilled_sort_matches_in_memory_sort() {
+        let keys = numbers(500);
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let order = SortOrder::new(true, None);
+        let (in_memory, spilled) = sort(&keys, order, usize::MAX, None);
+        assert!(!spilled);
+        let (on_disk, spilled) = sort(&keys, order, 1000, None);
+        assert!(spilled);
+        assert_eq!(in_memory, on_disk);
+        // Numeric order, with ties in input order
+        let parsed: Vec<(u32, usize)> = in_memory
+            .iter()
+            .map(|line| {
+                let (key, position) = line.split_once('#').unwrap();
+                (key.parse().unwrap(), position.parse().unwrap())
+            })
+            .collect();
+        assert!(parsed.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn key_turning_into_text_after_a_spill_resorts_the_runs() {
+        let mut keys = numbers(300);
+        keys.push("abc".to_string());
+        keys.push(String::new());
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let (lines, spilled) = sort(&keys, SortOrder::new(true, None), 1000, None);
+        assert!(spilled);
+
+        let mut expected: Vec<(&str, usize)> = keys.iter().copied().zip(0..).collect();
+        // Compared as text, with the NULL last
+        expected.sort_by_key(|&(key, i)| (key.is_empty(), key, i));
+        let expected: Vec<String> = expected
+            .iter()
+            .map(|(key, i)| format!("{}#{}", key, i))
+            .collect();
+        assert_eq!(lines, expected);
+        assert_eq!(lines[0], "0#0");
+        assert!(
+            lines.iter().position(|l| l.starts_with("10#"))
+                < lines.iter().position(|l| l.starts_with("9#"))
+        );
+    }
+
+    #[test]
+    fn top_n_matches_full_sort() {
+        let mut keys = numbers(400);
+        keys.insert(200, "x".to_string());
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        for limit in [1, 10, 50] {
+            for (first, last) in [(0, 200), (0, keys.len())] {
+                let keys = &keys[first..last];
+                let order = SortOrder::new(false, None);
+                let (full, _) = sort(keys, order, usize::MAX, None);
+                let (top, spilled) = sort(keys, order, usize::MAX, Some(limit));
+                assert!(!spilled);
+                assert_eq!(top, full[..limit]);
+                // A budget too small for the top rows falls back to spilling
+                let (top, _) = sort(keys, order, 200, Some(limit));
+                assert_eq!(top, full[..limit]);
+            }
+        }
+    }
+
+    #[test]
+    fn integers_beyond_float_precision_sort_exactly() {
+        let keys = [
+            "9007199254740993",
+            "9007199254740992",
+            "9007199254740995",
+            "9007199254740994",
+        ];
+        let (lines, _) = sort(&keys, SortOrder::new(true, None), usize::MAX, None);
+        let sorted: Vec<&str> = lines.iter().map(|l| l.split('#').next().unwrap()).collect();
+        assert_eq!(
+            sorted,
+            [
+                "9007199254740992",
+                "9007199254740993",
+                "9007199254740994",
+                "9007199254740995"
+            ]
+        );
+    }
+}
@@ -1,47 +1,1072 @@
-use regex::Regex;
+use crate::aggregates;
+use sqlparser::ast;
+use sqlparser::dialect::GenericDialect;
+use sqlparser::keywords::Keyword;
+use sqlparser::parser::{Parser, ParserError};
+use sqlparser::tokenizer::{Token, Tokenizer, Whitespace, Word};
+use std::error::Error;
+use std::fmt;
 
 // Struct to represent the parsed components of the SQL query
 #[derive(Debug)]
-pub struct ParsedCommand {
-    pub columns: Vec<String>,      // Selected columns or aggregate functions
-    pub data_file: String,         // Name of the CSV file to read
-    pub condition: Option<String>, // Optional condition for filtering rows
+pub struct Query {
+    pub distinct: bool,              // Whether duplicate output rows are removed
+    pub projection: Vec<SelectItem>, // Selected columns or aggregate functions
+    pub from: String,                // Name of the CSV file to read
+    pub selection: Option<Expr>,     // Optional condition for filtering rows
+    pub group_by: Vec<Expr>,         // GROUP BY keys, empty if not grouped
+    pub having: Option<Expr>,        // Optional condition for filtering groups
+    pub order_by: Vec<OrderByItem>,  // ORDER BY keys, empty if unordered
+    pub limit: Option<usize>,        // Maximum number of rows to output
+    pub offset: usize,               // Number of leading rows to skip
+}
+
+impl Query {
+    /// Returns true if LIMIT or OFFSET restricts the output rows.
+    pub fn is_limited(&self) -> bool {
+        self.limit.is_some() || self.offset > 0
+    }
+}
+
+// A single entry of the select list
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelectItem {
+    Wildcard,
+    Expr {
+        expr: Expr,
+        alias: Option<String>, // Output column name given with AS
+    },
+}
+
+// A single ORDER BY key
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderByItem {
+    pub expr: Expr,
+    pub ascending: bool,
+    pub nulls_first: Option<bool>, // None if NULLS FIRST/LAST was not given
+}
+
+// Expression tree used by the select list and the WHERE clause
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Column(String),
+    Number(String),
+    String(String),
+    Null,
+    // Only valid as the argument of COUNT(*)
+    Wildcard,
+    Function {
+        name: String,
+        args: Vec<Expr>,
+        distinct: bool, // Aggregate over distinct argument values only
+    },
+    BinaryOp {
+        left: Box<Expr>,
+        op: BinaryOperator,
+        right: Box<Expr>,
+    },
+    UnaryOp {
+        op: UnaryOperator,
+        expr: Box<Expr>,
+    },
+    InList {
+        expr: Box<Expr>,
+        list: Vec<Expr>,
+        negated: bool,
+    },
+    Between {
+        expr: Box<Expr>,
+        low: Box<Expr>,
+        high: Box<Expr>,
+        negated: bool,
+    },
+    IsNull {
+        expr: Box<Expr>,
+        negated: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOperator {
+    Not,
+    Minus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinaryOperator {
+    Lt,
+    Gt,
+    LtEq,
+    GtEq,
+    Eq,
+    NotEq,
+    And,
+    Or,
+    Like,
+    NotLike,
+    ILike,
+    NotILike,
+    RegexMatch,     // `~` or REGEXP
+    RegexIMatch,    // `~*`
+    RegexNotMatch,  // `!~` or NOT REGEXP
+    RegexNotIMatch, // `!~*`
+    Plus,
+    Minus,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+/// Error returned when a query cannot be parsed, pointing at the offending token if known.
+#[derive(Debug)]
+pub struct ParseError {
+    pub message: String,
+    pub position: Option<(usize, usize)>, // 1-based line and column
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.position {
+            Some((line, column)) => {
+                write!(f, "{} at line {}, column {}", self.message, line, column)
+            }
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+impl BinaryOperator {
+    /// Returns true for `+`, `-`, `*`, `/` and `%`.
+    pub fn is_arithmetic(&self) -> bool {
+        matches!(
+            self,
+            BinaryOperator::Plus
+                | BinaryOperator::Minus
+                | BinaryOperator::Multiply
+                | BinaryOperator::Divide
+                | BinaryOperator::Modulo
+        )
+    }
+}
+
+impl SelectItem {
+    /// Returns true for the `COUNT(*)` select item.
+    pub fn is_count_star(&self) -> bool {
+        match self {
+            SelectItem::Expr {
+                expr:
+                    Expr::Function {
+                        name,
+                        args,
+                        distinct,
+                    },
+                ..
+            } => name == "COUNT" && args.as_slice() == [Expr::Wildcard] && !distinct,
+            _ => false,
+        }
+    }
+
+    /// The output column name: the alias if there is one, the name of a selected column,
+    /// or otherwise the text of the expression.
+    pub fn label(&self) -> String {
+        match self {
+            SelectItem::Wildcard => "*".to_string(),
+            SelectItem::Expr {
+                alias: Some(alias), ..
+            } => alias.clone(),
+            SelectItem::Expr {
+                expr: Expr::Column(name),
+                ..
+            } => name.clone(),
+            SelectItem::Expr { expr, .. } => expr.to_string(),
+        }
+    }
+}
+
+impl Expr {
+    /// Collects all aggregate function calls in the expression.
+    pub fn aggregates<'a>(&'a self, aggregates: &mut Vec<&'a Expr>) {
+        match self {
+            Expr::Function { name, .. } if is_aggregate_function(name) => aggregates.push(self),
+            Expr::Function { args, .. } => args.iter().for_each(|arg| arg.aggregates(aggregates)),
+            Expr::BinaryOp { left, right, .. } => {
+                left.aggregates(aggregates);
+                right.aggregates(aggregates);
+            }
+            Expr::UnaryOp { expr, .. } => expr.aggregates(aggregates),
+            Expr::InList { expr, list, .. } => {
+                expr.aggregates(aggregates);
+                list.iter().for_each(|item| item.aggregates(aggregates));
+            }
+            Expr::Between {
+                expr, low, high, ..
+            } => {
+                expr.aggregates(aggregates);
+                low.aggregates(aggregates);
+                high.aggregates(aggregates);
+            }
+            Expr::IsNull { expr, .. } => expr.aggregates(aggregates),
+            Expr::Column(_) | Expr::Number(_) | Expr::String(_) | Expr::Null | Expr::Wildcard => {}
+        }
+    }
+
+    /// Collects the columns referenced outside of aggregate function calls.
+    pub fn bare_columns<'a>(&'a self, columns: &mut Vec<&'a str>) {
+        match self {
+            Expr::Column(name) => columns.push(name),
+            Expr::Function { name, .. } if is_aggregate_function(name) => {}
+            Expr::Function { args, .. } => args.iter().for_each(|arg| arg.bare_columns(columns)),
+            Expr::BinaryOp { left, right, .. } => {
+                left.bare_columns(columns);
+                right.bare_columns(columns);
+            }
+            Expr::UnaryOp { expr, .. } | Expr::IsNull { expr, .. } => expr.bare_columns(columns),
+            Expr::InList { expr, list, .. } => {
+                expr.bare_columns(columns);
+                list.iter().for_each(|item| item.bare_columns(columns));
+            }
+            Expr::Between {
+                expr, low, high, ..
+            } => {
+                expr.bare_columns(columns);
+                low.bare_columns(columns);
+                high.bare_columns(columns);
+            }
+            Expr::Number(_) | Expr::String(_) | Expr::Null | Expr::Wildcard => {}
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Column(name) if is_plain_identifier(name) => f.write_str(name),
+            Expr::Column(name) => write!(f, "\"{}\"", name.replace('"', "\"\"")),
+            Expr::Number(n) => f.write_str(n),
+            Expr::String(s) => write!(f, "'{}'", s.replace('\'', "''")),
+            Expr::Null => f.write_str("NULL"),
+            Expr::Wildcard => f.write_str("*"),
+            Expr::Function {
+                name,
+                args,
+                distinct,
+            } => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                let distinct = if *distinct { "DISTINCT " } else { "" };
+                write!(f, "{}({}{})", name, distinct, args.join(", "))
+            }
+            Expr::BinaryOp { left, op, right } => {
+                // Operators are left-associative, so only the right operand needs
+                // parentheses at the same precedence
+                write_operand(f, left, self.precedence())?;
+                write!(f, " {} ", op)?;
+                write_operand(f, right, self.precedence() + 1)
+            }
+            Expr::UnaryOp {
+                op: UnaryOperator::Minus,
+                expr,
+            } => {
+                // A nested minus is parenthesised, since `--` would start a comment
+                f.write_str("-")?;
+                write_operand(f, expr, self.precedence() + 1)
+            }
+            Expr::UnaryOp { op, expr } => {
+                write!(f, "{} ", op)?;
+                write_operand(f, expr, self.precedence())
+            }
+            Expr::InList {
+                expr,
+                list,
+                negated,
+            } => {
+                let list: Vec<String> = list.iter().map(|item| item.to_string()).collect();
+                write_operand(f, expr, self.precedence() + 1)?;
+                let not = if *negated { "NOT " } else { "" };
+                write!(f, " {}IN ({})", not, list.join(", "))
+            }
+            Expr::Between {
+                expr,
+                low,
+                high,
+                negated,
+            } => {
+                write_operand(f, expr, self.precedence() + 1)?;
+                write!(f, " {}BETWEEN ", if *negated { "NOT " } else { "" })?;
+                write_operand(f, low, self.precedence() + 1)?;
+                f.write_str(" AND ")?;
+                write_operand(f, high, self.precedence() + 1)
+            }
+            Expr::IsNull { expr, negated } => {
+                write_operand(f, expr, self.precedence() + 1)?;
+                f.write_str(if *negated { " IS NOT NULL" } else { " IS NULL" })
+            }
+        }
+    }
+}
+
+impl Expr {
+    // Binding strength of the expression's operator, used to parenthesise when displaying
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::BinaryOp {
+                op: BinaryOperator::Or,
+                ..
+            } => 1,
+            Expr::BinaryOp {
+                op: BinaryOperator::And,
+                ..
+            } => 2,
+            Expr::UnaryOp {
+                op: UnaryOperator::Not,
+                ..
+            } => 3,
+            Expr::BinaryOp {
+                op: BinaryOperator::Plus | BinaryOperator::Minus,
+                ..
+            } => 5,
+            Expr::BinaryOp {
+                op: BinaryOperator::Multiply | BinaryOperator::Divide | BinaryOperator::Modulo,
+                ..
+            } => 6,
+            Expr::UnaryOp {
+                op: UnaryOperator::Minus,
+                ..
+            } => 7,
+            Expr::Number(n) if n.starts_with('-') => 7,
+            Expr::BinaryOp { .. }
+            | Expr::InList { .. }
+            | Expr::Between { .. }
+            | Expr::IsNull { .. } => 4,
+            _ => u8::MAX,
+        }
+    }
+}
+
+// Returns true if a column name can be written without double quotes
+fn is_plain_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Writes an operand, in parentheses if it binds less tightly than `min_precedence`
+fn write_operand(f: &mut fmt::Formatter, operand: &Expr, min_precedence: u8) -> fmt::Result {
+    if operand.precedence() < min_precedence {
+        write!(f, "({})", operand)
+    } else {
+        write!(f, "{}", operand)
+    }
+}
+
+impl fmt::Display for UnaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            UnaryOperator::Not => "NOT",
+            UnaryOperator::Minus => "-",
+        })
+    }
+}
+
+impl fmt::Display for BinaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BinaryOperator::Lt => "<",
+            BinaryOperator::Gt => ">",
+            BinaryOperator::LtEq => "<=",
+            BinaryOperator::GtEq => ">=",
+            BinaryOperator::Eq => "=",
+            BinaryOperator::NotEq => "!=",
+            BinaryOperator::And => "AND",
+            BinaryOperator::Or => "OR",
+            BinaryOperator::Like => "LIKE",
+            BinaryOperator::NotLike => "NOT LIKE",
+            BinaryOperator::ILike => "ILIKE",
+            BinaryOperator::NotILike => "NOT ILIKE",
+            BinaryOperator::RegexMatch => "~",
+            BinaryOperator::RegexIMatch => "~*",
+            BinaryOperator::RegexNotMatch => "!~",
+            BinaryOperator::RegexNotIMatch => "!~*",
+            BinaryOperator::Plus => "+",
+            BinaryOperator::Minus => "-",
+            BinaryOperator::Multiply => "*",
+            BinaryOperator::Divide => "/",
+            BinaryOperator::Modulo => "%",
+        })
+    }
 }
 
 // Parses the SQL query string and extracts the columns, file, and condition
-pub fn parse_query(query: &str) -> Result<ParsedCommand, String> {
-    // Regular expression to match SELECT queries with an optional WHERE clause
-    let re = Regex::new(
-        r"(?i)SELECT\s+(?P<columns>.+?)\s+FROM\s+(?P<data_file>(?:[.\./]+)?[\w/._-]+\.csv)(?:\s+WHERE\s+(?P<condition>.+?))?\s*$"
-    ).unwrap();
-
-    if let Some(caps) = re.captures(query) {
-        // Split the selected columns by comma and trim whitespace
-        let columns = caps["columns"]
-            .split(',')
-            .map(|col| col.trim().to_string())
-            .collect();
-
-        // Extract the data file name and optional condition
-        let data_file = caps["data_file"].to_string();
-        let condition = caps.name("condition").map(|m| m.as_str().to_string());
-
-        Ok(ParsedCommand {
-            columns,
-            data_file,
-            condition,
+pub fn parse_query(query: &str) -> Result<Query, ParseError> {
+    let dialect = GenericDialect {};
+    let tokens = Tokenizer::new(&dialect, query)
+        .tokenize()
+        .map_err(|err| ParseError {
+            message: err.message,
+            position: Some((err.line as usize, err.col as usize)),
+        })?;
+    let tokens = merge_file_path(tokens);
+    let positions = token_positions(query, &tokens);
+    let tokens = replace_regexp(tokens);
+
+    let mut parser = Parser::new(tokens.clone(), &dialect);
+    let statement = parser.parse_statement().and_then(|statement| {
+        while parser.consume_token(&Token::SemiColon) {}
+        match parser.peek_token() {
+            Token::EOF => Ok(statement),
+            found => Err(ParserError::ParserError(format!(
+                "Expected end of statement, found: {}",
+                found
+            ))),
+        }
+    });
+
+    match statement {
+        Ok(ast::Statement::Query(query)) => convert_query(*query),
+        Ok(_) => Err(ParseError {
+            message: "Only SELECT statements are supported".to_string(),
+            position: None,
+        }),
+        Err(ParserError::ParserError(message)) | Err(ParserError::TokenizerError(message)) => {
+            // Find how far the parser got by draining what is left of the token stream
+            let significant: Vec<usize> = (0..tokens.len())
+                .filter(|&i| !matches!(tokens[i], Token::Whitespace(_)))
+                .collect();
+            let mut remaining = 0;
+            while parser.next_token() != Token::EOF {
+                remaining += 1;
+            }
+            let consumed = significant.len() - remaining.min(significant.len());
+
+            // The offending token is either the last consumed one or the next one; an
+            // unexpected end of input is reported at the end of the query
+            let found = message.rsplit("found: ").next().unwrap_or_default();
+            let offending = [consumed.checked_sub(1), Some(consumed)]
+                .into_iter()
+                .flatten()
+                .filter_map(|i| significant.get(i).copied())
+                .find(|&i| tokens[i].to_string() == found)
+                .or_else(|| {
+                    if found == Token::EOF.to_string() {
+                        return None;
+                    }
+                    consumed
+                        .checked_sub(1)
+                        .and_then(|i| significant.get(i).copied())
+                });
+
+            let position = match offending {
+                Some(i) => positions[i],
+                None => positions[tokens.len()],
+            };
+            Err(ParseError {
+                message,
+                position: Some(position),
+            })
+        }
+    }
+}
+
+// Helper function to check if a function name is a registered aggregate function
+pub fn is_aggregate_function(name: &str) -> bool {
+    aggregates::functions().read().unwrap().contains(name)
+}
+
+/// File paths such as `../data/small_wide.csv` are not valid SQL identifiers, so the
+/// tokens following the top-level `FROM` up to the next whitespace are merged into one
+/// identifier.
+fn merge_file_path(tokens: Vec<Token>) -> Vec<Token> {
+    let mut merged = Vec::with_capacity(tokens.len());
+    let mut iter = tokens.into_iter().peekable();
+    let mut depth = 0;
+
+    while let Some(token) = iter.next() {
+        match &token {
+            Token::LParen => depth += 1,
+            Token::RParen => depth -= 1,
+            _ => {}
+        }
+        let is_from = depth == 0 && matches!(&token, Token::Word(w) if w.keyword == Keyword::FROM);
+        merged.push(token);
+        if !is_from {
+            continue;
+        }
+
+        while let Some(Token::Whitespace(_)) = iter.peek() {
+            merged.push(iter.next().unwrap());
+        }
+
+        let mut path = Vec::new();
+        while let Some(token) = iter.peek() {
+            match token {
+                Token::Whitespace(_)
+                | Token::SemiColon
+                | Token::Comma
+                | Token::LParen
+                | Token::RParen => break,
+                _ => path.push(iter.next().unwrap()),
+            }
+        }
+
+        match path.as_slice() {
+            [] | [Token::Word(_)] => merged.append(&mut path),
+            _ => {
+                // Left unquoted so the token still spans exactly the original text
+                let value: String = path.iter().map(|t| t.to_string()).collect();
+                merged.push(Token::Word(Word {
+                    value,
+                    quote_style: None,
+                    keyword: Keyword::NoKeyword,
+                }));
+            }
+        }
+    }
+
+    merged
+}
+
+/// The parser only knows the `~` and `!~` regular expression operators, so the MySQL
+/// spellings `REGEXP` and `NOT REGEXP` are replaced by them. Tokens are replaced one for
+/// one, with `NOT` becoming a space, so that token positions stay valid.
+fn replace_regexp(mut tokens: Vec<Token>) -> Vec<Token> {
+    let is_word = |token: &Token, keyword: &str| {
+        matches!(
+            token,
+            Token::Word(w) if w.quote_style.is_none() && w.value.eq_ignore_ascii_case(keyword)
+        )
+    };
+    for i in 0..tokens.len() {
+        if !is_word(&tokens[i], "REGEXP") {
+            continue;
+        }
+        let previous = (0..i)
+            .rev()
+            .find(|&j| !matches!(tokens[j], Token::Whitespace(_)));
+        match previous {
+            Some(j) if is_word(&tokens[j], "NOT") => {
+                tokens[j] = Token::Whitespace(Whitespace::Space);
+                tokens[i] = Token::ExclamationMarkTilde;
+            }
+            _ => tokens[i] = Token::Tilde,
+        }
+    }
+    tokens
+}
+
+/// Computes the 1-based (line, column) at which each token starts, plus one trailing entry
+/// for the end of the query.
+fn token_positions(query: &str, tokens: &[Token]) -> Vec<(usize, usize)> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut positions = Vec::with_capacity(tokens.len() + 1);
+    let mut offset = 0;
+
+    let position_of = |offset: usize| {
+        let before = &chars[..offset.min(chars.len())];
+        let line = before.iter().filter(|&&c| c == '\n').count() + 1;
+        let column = before.iter().rev().take_while(|&&c| c != '\n').count() + 1;
+        (line, column)
+    };
+
+    for token in tokens {
+        positions.push(position_of(offset));
+        offset += match token {
+            Token::SingleQuotedString(s) => s.chars().count() + s.matches('\'').count() + 2,
+            Token::NationalStringLiteral(s) => s.chars().count() + s.matches('\'').count() + 3,
+            Token::HexStringLiteral(s) => s.chars().count() + 3,
+            Token::Number(s, long) => s.chars().count() + *long as usize,
+            Token::Whitespace(Whitespace::Newline) if chars.get(offset) == Some(&'\r') => {
+                if chars.get(offset + 1) == Some(&'\n') {
+                    2
+                } else {
+                    1
+                }
+            }
+            other => other.to_string().chars().count(),
+        };
+    }
+    positions.push(position_of(offset));
+
+    positions
+}
+
+fn convert_query(query: ast::Query) -> Result<Query, ParseError> {
+    let select = match query.body {
+        ast::SetExpr::Select(select) => *select,
+        other => return Err(unsupported("query body", &other)),
+    };
+
+    let limit = query
+        .limit
+        .map(|limit| row_count(limit, "LIMIT"))
+        .transpose()?;
+    let offset = query
+        .offset
+        .map(|offset| row_count(offset.value, "OFFSET"))
+        .transpose()?
+        .unwrap_or(0);
+
+    let from = match select.from.as_slice() {
+        [ast::TableWithJoins {
+            relation: ast::TableFactor::Table { name, .. },
+            joins,
+        }] if joins.is_empty() => name
+            .0
+            .iter()
+            .map(|ident| ident.value.as_str())
+            .collect::<Vec<_>>()
+            .join("."),
+        [] => return Err(unsupported("query", &"SELECT without FROM")),
+        _ => return Err(unsupported("FROM clause", &"joins or subqueries")),
+    };
+
+    let projection = select
+        .projection
+        .into_iter()
+        .map(|item| match item {
+            ast::SelectItem::Wildcard => Ok(SelectItem::Wildcard),
+            ast::SelectItem::UnnamedExpr(expr) => Ok(SelectItem::Expr {
+                expr: convert_expr(expr)?,
+                alias: None,
+            }),
+            ast::SelectItem::ExprWithAlias { expr, alias } => Ok(SelectItem::Expr {
+                expr: convert_expr(expr)?,
+                alias: Some(alias.value),
+            }),
+            other => Err(unsupported("select item", &other)),
         })
-    } else {
-        Err("Invalid SQL Query format".to_string())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let selection = select.selection.map(convert_expr).transpose()?;
+    let group_by = select
+        .group_by
+        .into_iter()
+        .map(convert_expr)
+        .collect::<Result<Vec<_>, _>>()?;
+    let having = select.having.map(convert_expr).transpose()?;
+    let order_by = query
+        .order_by
+        .into_iter()
+        .map(|item| {
+            Ok(OrderByItem {
+                expr: resolve_order_key(convert_expr(item.expr)?, &projection)?,
+                ascending: item.asc.unwrap_or(true),
+                nulls_first: item.nulls_first,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Which of several duplicate rows an ORDER BY key would come from is undefined
+    if select.distinct && !projection.contains(&SelectItem::Wildcard) {
+        if let Some(item) = order_by.iter().find(|item| {
+            !projection
+                .iter()
+                .any(|p| matches!(p, SelectItem::Expr { expr, .. } if *expr == item.expr))
+        }) {
+            return Err(ParseError {
+                message: format!(
+                    "ORDER BY expression must appear in the select list for SELECT DISTINCT: {}",
+                    item.expr
+                ),
+                position: None,
+            });
+        }
+    }
+
+    // Outside of aggregates, an aggregate query can only refer to the grouped columns
+    let select_exprs: Vec<&Expr> = projection
+        .iter()
+        .filter_map(|item| match item {
+            SelectItem::Expr { expr, .. } => Some(expr),
+            SelectItem::Wildcard => None,
+        })
+        .collect();
+    let mut aggregates = Vec::new();
+    for expr in &select_exprs {
+        expr.aggregates(&mut aggregates);
+    }
+    if !group_by.is_empty() || having.is_some() || !aggregates.is_empty() {
+        let mut columns = Vec::new();
+        let order_by_exprs = order_by.iter().map(|item| &item.expr);
+        for expr in select_exprs
+            .iter()
+            .copied()
+            .chain(&having)
+            .chain(order_by_exprs)
+        {
+            expr.bare_columns(&mut columns);
+        }
+        if let Some(column) = columns
+            .iter()
+            .find(|&&column| !group_by.contains(&Expr::Column(column.to_string())))
+        {
+            return Err(ParseError {
+                message: format!(
+                    "Column must appear in GROUP BY or be used in an aggregate function: {}",
+                    column
+                ),
+                position: None,
+            });
+        }
+    }
+
+    Ok(Query {
+        distinct: select.distinct,
+        projection,
+        from,
+        selection,
+        group_by,
+        having,
+        order_by,
+        limit,
+        offset,
+    })
+}
+
+// Replaces an ORDER BY column that names a select list alias with the aliased expression,
+// and an integer with the select list item at that 1-based position. Other literals would
+// give every row the same key, so they are rejected.
+fn resolve_order_key(expr: Expr, projection: &[SelectItem]) -> Result<Expr, ParseError> {
+    let error = |message: String| ParseError {
+        message,
+        position: None,
+    };
+    match &expr {
+        Expr::Column(name) => {
+            for item in projection {
+                if let SelectItem::Expr {
+                    expr: aliased,
+                    alias: Some(alias),
+                } = item
+                {
+                    if alias == name {
+                        return Ok(aliased.clone());
+                    }
+                }
+            }
+            Ok(expr)
+        }
+        Expr::Number(n) => {
+            let position = n.parse::<usize>().ok().filter(|&position| position > 0);
+            match position.and_then(|position| projection.get(position - 1)) {
+                Some(SelectItem::Expr { expr, .. }) => Ok(expr.clone()),
+                Some(SelectItem::Wildcard) => {
+                    Err(error(format!("ORDER BY position cannot refer to *: {}", n)))
+                }
+                None => Err(error(format!(
+                    "ORDER BY position is not in select list: {}",
+                    n
+                ))),
+            }
+        }
+        Expr::String(_) | Expr::Null => Err(error(format!(
+            "ORDER BY key must be a column or expression, found: {}",
+            expr
+        ))),
+        _ => Ok(expr),
     }
 }
 
-// Helper function to check if a column specifies an aggregate function
-pub fn is_aggregate_function(column: &str) -> bool {
-    column == "COUNT(*)"
-        || column.starts_with("SUM(")
-        || column.starts_with("AVG(")
-        || column.starts_with("MIN(")
-        || column.starts_with("MAX(")
-        || column.starts_with("COUNT(")
+// Converts the argument of LIMIT or OFFSET, which must be a non-negative integer literal
+fn row_count(expr: ast::Expr, clause: &str) -> Result<usize, ParseError> {
+    match &expr {
+        ast::Expr::Value(ast::Value::Number(n, _)) => n.parse().map_err(|_| ParseError {
+            message: format!("{} must be a non-negative integer, found: {}", clause, n),
+            position: None,
+        }),
+        _ => Err(unsupported(&format!("{} expression", clause), &expr)),
+    }
+}
+
+fn convert_expr(expr: ast::Expr) -> Result<Expr, ParseError> {
+    match expr {
+        ast::Expr::Identifier(ident) => Ok(Expr::Column(ident.value)),
+        ast::Expr::Wildcard => Ok(Expr::Wildcard),
+        ast::Expr::Nested(expr) => convert_expr(*expr),
+        ast::Expr::Value(ast::Value::Number(n, _)) => Ok(Expr::Number(n)),
+        ast::Expr::Value(ast::Value::SingleQuotedString(s)) => Ok(Expr::String(s)),
+        ast::Expr::Value(ast::Value::Null) => Ok(Expr::Null),
+        ast::Expr::IsNull(expr) => Ok(Expr::IsNull {
+            expr: Box::new(convert_expr(*expr)?),
+            negated: false,
+        }),
+        ast::Expr::IsNotNull(expr) => Ok(Expr::IsNull {
+            expr: Box::new(convert_expr(*expr)?),
+            negated: true,
+        }),
+        ast::Expr::Function(function) if function.over.is_none() => {
+            let name = function.name.to_string().to_uppercase();
+            let args = function
+                .args
+                .into_iter()
+                .map(|arg| match arg {
+                    ast::FunctionArg::Unnamed(expr) => convert_expr(expr),
+                    named => Err(unsupported("function argument", &named)),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Expr::Function {
+                name,
+                args,
+                distinct: function.distinct,
+            })
+        }
+        ast::Expr::UnaryOp {
+            op: ast::UnaryOperator::Not,
+            expr,
+        } => Ok(Expr::UnaryOp {
+            op: UnaryOperator::Not,
+            expr: Box::new(convert_expr(*expr)?),
+        }),
+        ast::Expr::UnaryOp {
+            op: ast::UnaryOperator::Minus,
+            expr,
+        } => Ok(match convert_expr(*expr)? {
+            // Negative numbers are literals of their own
+            Expr::Number(n) if !n.starts_with('-') => Expr::Number(format!("-{}", n)),
+            expr => Expr::UnaryOp {
+                op: UnaryOperator::Minus,
+                expr: Box::new(expr),
+            },
+        }),
+        ast::Expr::UnaryOp {
+            op: ast::UnaryOperator::Plus,
+            expr,
+        } => convert_expr(*expr),
+        ast::Expr::InList {
+            expr,
+            list,
+            negated,
+        } => Ok(Expr::InList {
+            expr: Box::new(convert_expr(*expr)?),
+            list: list
+                .into_iter()
+                .map(convert_expr)
+                .collect::<Result<Vec<_>, _>>()?,
+            negated,
+        }),
+        ast::Expr::Between {
+            expr,
+            negated,
+            low,
+            high,
+        } => Ok(Expr::Between {
+            expr: Box::new(convert_expr(*expr)?),
+            low: Box::new(convert_expr(*low)?),
+            high: Box::new(convert_expr(*high)?),
+            negated,
+        }),
+        ast::Expr::BinaryOp { left, op, right } => {
+            let op = match op {
+                ast::BinaryOperator::Lt => BinaryOperator::Lt,
+                ast::BinaryOperator::Gt => BinaryOperator::Gt,
+                ast::BinaryOperator::LtEq => BinaryOperator::LtEq,
+                ast::BinaryOperator::GtEq => BinaryOperator::GtEq,
+                ast::BinaryOperator::Eq => BinaryOperator::Eq,
+                ast::BinaryOperator::NotEq => BinaryOperator::NotEq,
+                ast::BinaryOperator::And => BinaryOperator::And,
+                ast::BinaryOperator::Or => BinaryOperator::Or,
+                ast::BinaryOperator::Like => BinaryOperator::Like,
+                ast::BinaryOperator::NotLike => BinaryOperator::NotLike,
+                ast::BinaryOperator::ILike => BinaryOperator::ILike,
+                ast::BinaryOperator::NotILike => BinaryOperator::NotILike,
+                ast::BinaryOperator::PGRegexMatch => BinaryOperator::RegexMatch,
+                ast::BinaryOperator::PGRegexIMatch => BinaryOperator::RegexIMatch,
+                ast::BinaryOperator::PGRegexNotMatch => BinaryOperator::RegexNotMatch,
+                ast::BinaryOperator::PGRegexNotIMatch => BinaryOperator::RegexNotIMatch,
+                ast::BinaryOperator::Plus => BinaryOperator::Plus,
+                ast::BinaryOperator::Minus => BinaryOperator::Minus,
+                ast::BinaryOperator::Multiply => BinaryOperator::Multiply,
+                ast::BinaryOperator::Divide => BinaryOperator::Divide,
+                ast::BinaryOperator::Modulo => BinaryOperator::Modulo,
+                other => return Err(unsupported("operator", &other)),
+            };
+            Ok(Expr::BinaryOp {
+                left: Box::new(convert_expr(*left)?),
+                op,
+                right: Box::new(convert_expr(*right)?),
+            })
+        }
+        other => Err(unsupported("expression", &other)),
+    }
+}
+
+fn unsupported(what: &str, node: &dyn fmt::Display) -> ParseError {
+    ParseError {
+        message: format!("Unsupported {}: {}", what, node),
+        position: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order_by(sql: &str) -> Result<Vec<Expr>, String> {
+        let query = parse_query(sql).map_err(|err| err.message)?;
+        Ok(query.order_by.into_iter().map(|item| item.expr).collect())
+    }
+
+    fn column(name: &str) -> Expr {
+        Expr::Column(name.to_string())
+    }
+
+    #[test]
+    fn order_by_resolves_aliases_and_positions() {
+        assert_eq!(
+            order_by("SELECT name, price FROM a.csv ORDER BY 2, 1 DESC"),
+            Ok(vec![column("price"), column("name")])
+        );
+        assert_eq!(
+            order_by("SELECT price * 2 AS double FROM a.csv ORDER BY double"),
+            order_by("SELECT price * 2 AS double FROM a.csv ORDER BY 1")
+        );
+        assert_eq!(
+            order_by("SELECT region, SUM(price) FROM a.csv GROUP BY region ORDER BY 2"),
+            order_by("SELECT region, SUM(price) FROM a.csv GROUP BY region ORDER BY SUM(price)")
+        );
+        // An ordinal resolves to a selected expression, so DISTINCT accepts it
+        assert!(order_by("SELECT DISTINCT name FROM a.csv ORDER BY 1").is_ok());
+    }
+
+    #[test]
+    fn order_by_rejects_constants() {
+        let errors = [
+            (
+                "SELECT name FROM a.csv ORDER BY 2",
+                "ORDER BY position is not in select list: 2",
+            ),
+            (
+                "SELECT name FROM a.csv ORDER BY 0",
+                "ORDER BY position is not in select list: 0",
+            ),
+            (
+                "SELECT name FROM a.csv ORDER BY 1.5",
+                "ORDER BY position is not in select list: 1.5",
+            ),
+            (
+                "SELECT * FROM a.csv ORDER BY 1",
+                "ORDER BY position cannot refer to *: 1",
+            ),
+            (
+                "SELECT name FROM a.csv ORDER BY 'name'",
+                "ORDER BY key must be a column or expression, found: 'name'",
+            ),
+            (
+                "SELECT name FROM a.csv ORDER BY NULL",
+                "ORDER BY key must be a column or expression, found: NULL",
+            ),
+        ];
+        for (sql, message) in errors {
+            assert_eq!(order_by(sql), Err(message.to_string()), "{}", sql);
+        }
+    }
+
+    fn error(sql: &str) -> (String, Option<(usize, usize)>) {
+        let err = parse_query(sql).unwrap_err();
+        (err.message, err.position)
+    }
+
+    #[test]
+    fn reports_the_position_of_the_offending_token() {
+        let (message, position) = error("SELECT SUM(price FROM a.csv");
+        assert_eq!(message, "Expected ), found: FROM");
+        assert_eq!(position, Some((1, 18)));
+        assert_eq!(
+            error("SELECT name FROM a.csv WHERE price > > 2").1,
+            Some((1, 38))
+        );
+        assert_eq!(error("SELECT name FROM a.csv LIMIT 1 x").1, Some((1, 32)));
+        assert_eq!(
+            parse_query("SELECT SUM(price FROM a.csv")
+                .unwrap_err()
+                .to_string(),
+            "Expected ), found: FROM at line 1, column 18"
+        );
+    }
+
+    #[test]
+    fn reports_an_unexpected_end_at_the_end_of_the_query() {
+        let (message, position) = error("SELECT name FROM a.csv WHERE");
+        assert_eq!(message, "Expected an expression:, found: EOF");
+        assert_eq!(position, Some((1, 29)));
+        assert_eq!(
+            error("SELECT name FROM a.csv WHERE price >  ").1,
+            Some((1, 39))
+        );
+        assert_eq!(error("SELECT SUM(price").1, Some((1, 17)));
+    }
+
+    #[test]
+    fn counts_lines_and_characters() {
+        let sql = "SELECT name,\n       price\nFROM a.csv\nWHERE price = = 2";
+        assert_eq!(error(sql).1, Some((4, 15)));
+        // Columns count characters, not bytes
+        let sql = "SELECT name FROM a.csv WHERE name = 'h\u{e9}llo' AND )";
+        assert_eq!(error(sql).1, Some((1, 49)));
+        let sql = "SELECT name FROM a.csv WHERE name = 'it''s' AND )";
+        assert_eq!(error(sql).1, Some((1, 49)));
+        let sql = "SELECT name\r\nFROM a.csv\r\nWHERE = 1";
+        assert_eq!(error(sql).1, Some((3, 7)));
+        // Tokenizer errors carry their own position
+        assert_eq!(
+            error("SELECT name\nFROM a.csv WHERE name = 'abc").1,
+            Some((2, 25))
+        );
+    }
+
+    #[test]
+    fn reads_file_paths_after_from() {
+        let query = parse_query("SELECT name FROM ../dir/file-1.csv WHERE price > 1").unwrap();
+        assert_eq!(query.from, "../dir/file-1.csv");
+        assert_eq!(query.selection.unwrap().to_string(), "price > 1");
+        assert_eq!(
+            error("SELECT name FROM ../dir/file-1.csv WHERE )").1,
+            Some((1, 42))
+        );
+        let query = parse_query("SELECT COUNT(*) FROM data.v2.csv").unwrap();
+        assert_eq!(query.from, "data.v2.csv");
+    }
+
+    fn condition_op(sql: &str) -> BinaryOperator {
+        let query = parse_query(&format!("SELECT * FROM a.csv WHERE {}", sql)).unwrap();
+        match query.selection.unwrap() {
+            Expr::BinaryOp { op, .. } => op,
+            other => panic!("{} parsed as {:?}", sql, other),
+        }
+    }
+
+    #[test]
+    fn rewrites_regexp_operators() {
+        assert_eq!(condition_op("name REGEXP '^a'"), BinaryOperator::RegexMatch);
+        assert_eq!(condition_op("name regexp '^a'"), BinaryOperator::RegexMatch);
+        assert_eq!(
+            condition_op("name NOT REGEXP '^a'"),
+            BinaryOperator::RegexNotMatch
+        );
+        assert_eq!(
+            condition_op("name not\n  Regexp '^a'"),
+            BinaryOperator::RegexNotMatch
+        );
+        assert_eq!(condition_op("name ~* '^a'"), BinaryOperator::RegexIMatch);
+        assert_eq!(
+            condition_op("name !~* '^a'"),
+            BinaryOperator::RegexNotIMatch
+        );
+        assert_eq!(condition_op("name ILIKE 'a%'"), BinaryOperator::ILike);
+        assert_eq!(condition_op("name NOT LIKE 'a%'"), BinaryOperator::NotLike);
+        // Quoted identifiers are names, not operators
+        assert_eq!(condition_op("\"regexp\" = 1"), BinaryOperator::Eq);
+    }
+
+    #[test]
+    fn regexp_rewrite_keeps_error_positions() {
+        let sql = "SELECT name FROM a.csv WHERE name NOT REGEXP 'a' AND )";
+        assert_eq!(error(sql).1, Some((1, 54)));
+        let sql = "SELECT name FROM a.csv WHERE name REGEXP 'a' AND )";
+        assert_eq!(error(sql).1, Some((1, 50)));
+        let sql = "SELECT name FROM a.csv\nWHERE name NOT REGEXP";
+        assert_eq!(error(sql).1, Some((2, 22)));
+    }
 }
@@ -0,0 +1,636 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+// Decimals with more significant digits than this are read as floats
+const MAX_DECIMAL_DIGITS: usize = 38;
+const MICROS_PER_SECOND: i64 = 1_000_000;
+const MICROS_PER_DAY: i64 = 86_400 * MICROS_PER_SECOND;
+
+/// A typed field value.
+///
+/// Fields are typed by their contents: empty fields are NULL, integers that fit in 64 bits
+/// are `Int64`, other plain decimal numbers are exact `Decimal`s, and numbers with an
+/// exponent (or `inf`/`NaN`) are `Float64`. `true`/`false`, ISO dates (`2024-01-31`) and
+/// timestamps (`2024-01-31 12:00:00[.ffffff]`, optionally with `T` and `Z`) are recognised,
+/// and everything else is `Text`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Int64(i64),
+    Float64(f64),
+    Decimal(i128, u32), // Mantissa and scale: the value is mantissa / 10^scale
+    Text(String),
+    Bool(bool),
+    Date(i32),      // Days since 1970-01-01
+    Timestamp(i64), // Microseconds since 1970-01-01 00:00:00
+}
+
+impl Value {
+    /// Infers the type of a raw field and parses it.
+    pub fn parse(field: &str) -> Value {
+        let trimmed = field.trim();
+        if trimmed.is_empty() {
+            return Value::Null;
+        }
+        if let Some(value) = parse_number(trimmed) {
+            return value;
+        }
+        if trimmed.eq_ignore_ascii_case("true") {
+            return Value::Bool(true);
+        }
+        if trimmed.eq_ignore_ascii_case("false") {
+            return Value::Bool(false);
+        }
+        if let Some(value) = parse_date_time(trimmed) {
+            return value;
+        }
+        Value::Text(field.to_string())
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    /// Returns the value as a float if it is numeric.
+    pub fn to_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int64(i) => Some(*i as f64),
+            Value::Float64(f) => Some(*f),
+            Value::Decimal(mantissa, scale) => Some(*mantissa as f64 / 10f64.powi(*scale as i32)),
+            _ => None,
+        }
+    }
+
+    /// Compares two values, or returns None if either is NULL or they are not comparable.
+    ///
+    /// Numbers of different types compare by value, exactly unless a float is involved,
+    /// and dates compare with timestamps as midnight of that day.
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Int64(a), Value::Int64(b)) => Some(a.cmp(b)),
+            (Value::Float64(_), _) | (_, Value::Float64(_)) => {
+                self.to_f64()?.partial_cmp(&other.to_f64()?)
+            }
+            (Value::Int64(_) | Value::Decimal(..), Value::Int64(_) | Value::Decimal(..)) => {
+                let (a, a_scale) = self.as_decimal()?;
+                let (b, b_scale) = other.as_decimal()?;
+                match align_scales(a, a_scale, b, b_scale) {
+                    Some((a, b, _)) => Some(a.cmp(&b)),
+                    None => self.to_f64()?.partial_cmp(&other.to_f64()?),
+                }
+            }
+            (Value::Text(a), Value::Text(b)) => Some(a.cmp(b)),
+            (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+            (Value::Date(a), Value::Date(b)) => Some(a.cmp(b)),
+            (Value::Timestamp(a), Value::Timestamp(b)) => Some(a.cmp(b)),
+            (Value::Date(a), Value::Timestamp(b)) => Some((*a as i64 * MICROS_PER_DAY).cmp(b)),
+            (Value::Timestamp(a), Value::Date(b)) => Some(a.cmp(&(*b as i64 * MICROS_PER_DAY))),
+            _ => None,
+        }
+    }
+
+    /// Orders any two values, even ones `compare` cannot: numbers come first, with NaN
+    /// after every other number, then booleans, dates and timestamps, text, and NULL last.
+    /// Agrees with `compare` whenever that returns an ordering.
+    pub fn total_order(&self, other: &Value) -> Ordering {
+        self.compare(other).unwrap_or_else(|| {
+            let rank = |value: &Value| match value {
+                Value::Int64(_) | Value::Decimal(..) => 0,
+                Value::Float64(x) if !x.is_nan() => 0,
+                Value::Float64(_) => 1,
+                Value::Bool(_) => 2,
+                Value::Date(_) | Value::Timestamp(_) => 3,
+                Value::Text(_) => 4,
+                Value::Null => 5,
+            };
+            rank(self).cmp(&rank(other))
+        })
+    }
+
+    /// Adds two numbers, staying exact for integers and decimals as long as they fit.
+    /// Returns None if either value is not a number.
+    pub fn add(&self, other: &Value) -> Option<Value> {
+        match (self, other) {
+            (Value::Int64(a), Value::Int64(b)) => Some(match a.checked_add(*b) {
+                Some(sum) => Value::Int64(sum),
+                None => Value::Decimal(*a as i128 + *b as i128, 0),
+            }),
+            (Value::Float64(_), _) | (_, Value::Float64(_)) => {
+                Some(Value::Float64(self.to_f64()? + other.to_f64()?))
+            }
+            _ => {
+                let (a, a_scale) = self.as_decimal()?;
+                let (b, b_scale) = other.as_decimal()?;
+                match align_scales(a, a_scale, b, b_scale)
+                    .and_then(|(a, b, scale)| Some((a.checked_add(b)?, scale)))
+                {
+                    Some((sum, scale)) => Some(Value::Decimal(sum, scale)),
+                    None => Some(Value::Float64(self.to_f64()? + other.to_f64()?)),
+                }
+            }
+        }
+    }
+
+    /// Subtracts two numbers, exactly like `add`.
+    pub fn subtract(&self, other: &Value) -> Option<Value> {
+        self.add(&other.negate()?)
+    }
+
+    /// Multiplies two numbers, staying exact for integers and decimals as long as they fit.
+    pub fn multiply(&self, other: &Value) -> Option<Value> {
+        match (self, other) {
+            (Value::Int64(a), Value::Int64(b)) => Some(match a.checked_mul(*b) {
+                Some(product) => Value::Int64(product),
+                None => Value::Decimal(*a as i128 * *b as i128, 0),
+            }),
+            (Value::Float64(_), _) | (_, Value::Float64(_)) => {
+                Some(Value::Float64(self.to_f64()? * other.to_f64()?))
+            }
+            _ => {
+                let (a, a_scale) = self.as_decimal()?;
+                let (b, b_scale) = other.as_decimal()?;
+                let scale = a_scale + b_scale;
+                match a.checked_mul(b) {
+                    Some(product) if scale as usize <= MAX_DECIMAL_DIGITS => {
+                        Some(Value::Decimal(product, scale))
+                    }
+                    _ => Some(Value::Float64(self.to_f64()? * other.to_f64()?)),
+                }
+            }
+        }
+    }
+
+    /// Divides two numbers. The quotient is always a float, and NULL for division by zero.
+    pub fn divide(&self, other: &Value) -> Option<Value> {
+        let (a, b) = (self.to_f64()?, other.to_f64()?);
+        Some(if b == 0.0 {
+            Value::Null
+        } else {
+            Value::Float64(a / b)
+        })
+    }
+
+    /// Returns the remainder of dividing two numbers, which has the sign of the dividend,
+    /// or NULL for division by zero. Exact for integers and decimals.
+    pub fn remainder(&self, other: &Value) -> Option<Value> {
+        let exact = match (self.as_decimal(), other.as_decimal()) {
+            (Some((a, a_scale)), Some((b, b_scale))) => align_scales(a, a_scale, b, b_scale),
+            _ => None,
+        };
+        match (self, other, exact) {
+            (_, _, Some((_, 0, _))) => Some(Value::Null),
+            (Value::Int64(a), Value::Int64(b), _) => {
+                Some(Value::Int64(a.checked_rem(*b).unwrap_or(0)))
+            }
+            (_, _, Some((a, b, scale))) => Some(Value::Decimal(a % b, scale)),
+            _ => {
+                let (a, b) = (self.to_f64()?, other.to_f64()?);
+                Some(if b == 0.0 {
+                    Value::Null
+                } else {
+                    Value::Float64(a % b)
+                })
+            }
+        }
+    }
+
+    /// Negates a number, or returns None if the value is not a number.
+    pub fn negate(&self) -> Option<Value> {
+        match self {
+            Value::Int64(i) => Some(match i.checked_neg() {
+                Some(negated) => Value::Int64(negated),
+                None => Value::Decimal(-(*i as i128), 0),
+            }),
+            Value::Float64(x) => Some(Value::Float64(-x)),
+            Value::Decimal(mantissa, scale) => Some(match mantissa.checked_neg() {
+                Some(negated) => Value::Decimal(negated, *scale),
+                None => Value::Float64(-self.to_f64()?),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns a key that is the same for two values exactly when they compare equal, or
+    /// None for NULL and NaN, which equal nothing. Used to look values up in hash sets.
+    pub fn equality_key(&self) -> Option<String> {
+        match self {
+            Value::Null => None,
+            Value::Int64(i) => Some(format!("n:{}", i)),
+            Value::Float64(x) if x.is_nan() => None,
+            Value::Float64(x) if x.fract() == 0.0 && x.abs() < i64::MAX as f64 => {
+                Some(format!("n:{}", *x as i64))
+            }
+            Value::Float64(x) => Some(format!("n:{}", x)),
+            Value::Decimal(mantissa, scale) => {
+                let (mut mantissa, mut scale) = (*mantissa, *scale);
+                while scale > 0 && mantissa % 10 == 0 {
+                    mantissa /= 10;
+                    scale -= 1;
+                }
+                Some(format!("n:{}", Value::Decimal(mantissa, scale)))
+            }
+            Value::Text(s) => Some(format!("t:{}", s)),
+            Value::Bool(b) => Some(format!("b:{}", b)),
+            Value::Date(days) => Some(format!("ts:{}", *days as i64 * MICROS_PER_DAY)),
+            Value::Timestamp(micros) => Some(format!("ts:{}", micros)),
+        }
+    }
+
+    // Returns integers and decimals as mantissa and scale
+    fn as_decimal(&self) -> Option<(i128, u32)> {
+        match self {
+            Value::Int64(i) => Some((*i as i128, 0)),
+            Value::Decimal(mantissa, scale) => Some((*mantissa, *scale)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => Ok(()),
+            Value::Int64(i) => write!(f, "{}", i),
+            Value::Float64(x) => write!(f, "{}", x),
+            Value::Decimal(mantissa, scale) => {
+                let digits = mantissa.unsigned_abs().to_string();
+                let scale = *scale as usize;
+                let digits = format!("{:0>width$}", digits, width = scale + 1);
+                let (whole, fraction) = digits.split_at(digits.len() - scale);
+                let sign = if *mantissa < 0 { "-" } else { "" };
+                if fraction.is_empty() {
+                    write!(f, "{}{}", sign, whole)
+                } else {
+                    write!(f, "{}{}.{}", sign, whole, fraction)
+                }
+            }
+            Value::Text(s) => f.write_str(s),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Date(days) => {
+                let (year, month, day) = civil_from_days(*days as i64);
+                write!(f, "{:04}-{:02}-{:02}", year, month, day)
+            }
+            Value::Timestamp(micros) => {
+                let days = micros.div_euclid(MICROS_PER_DAY);
+                let micros = micros.rem_euclid(MICROS_PER_DAY);
+                let (year, month, day) = civil_from_days(days);
+                let seconds = micros / MICROS_PER_SECOND;
+                write!(
+                    f,
+                    "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                    year,
+                    month,
+                    day,
+                    seconds / 3600,
+                    seconds / 60 % 60,
+                    seconds % 60
+                )?;
+                match micros % MICROS_PER_SECOND {
+                    0 => Ok(()),
+                    fraction => write!(f, ".{:06}", fraction),
+                }
+            }
+        }
+    }
+}
+
+// Brings two decimals to the same scale, returning None on overflow
+fn align_scales(a: i128, a_scale: u32, b: i128, b_scale: u32) -> Option<(i128, i128, u32)> {
+    let scale = a_scale.max(b_scale);
+    let a = a.checked_mul(10i128.checked_pow(scale - a_scale)?)?;
+    let b = b.checked_mul(10i128.checked_pow(scale - b_scale)?)?;
+    Some((a, b, scale))
+}
+
+// Parses integers, plain decimals and floats
+fn parse_number(s: &str) -> Option<Value> {
+    if let Ok(i) = s.parse::<i64>() {
+        return Some(Value::Int64(i));
+    }
+
+    let digits = s.strip_prefix(['-', '+']).unwrap_or(s);
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    let is_plain = !(whole.is_empty() && fraction.is_empty())
+        && whole.bytes().all(|b| b.is_ascii_digit())
+        && fraction.bytes().all(|b| b.is_ascii_digit());
+    if is_plain && whole.len() + fraction.len() <= MAX_DECIMAL_DIGITS {
+        let mantissa: i128 = format!("{}{}", whole, fraction).parse().ok()?;
+        let mantissa = if s.starts_with('-') {
+            -mantissa
+        } else {
+            mantissa
+        };
+        return Some(Value::Decimal(mantissa, fraction.len() as u32));
+    }
+
+    // Only accept float syntax Rust agrees with, but not words like "infinity" in text
+    let first = digits.bytes().next()?;
+    if first.is_ascii_digit() || first == b'.' || digits.eq_ignore_ascii_case("inf") {
+        return s.parse::<f64>().ok().map(Value::Float64);
+    }
+    if digits.eq_ignore_ascii_case("nan") {
+        return Some(Value::Float64(f64::NAN));
+    }
+    None
+}
+
+// Parses "YYYY-MM-DD" as a date and "YYYY-MM-DD[ T]HH:MM:SS[.ffffff][Z]" as a timestamp
+fn parse_date_time(s: &str) -> Option<Value> {
+    let bytes = s.as_bytes();
+    // Checking for ASCII first keeps the slices below on character boundaries
+    if bytes.len() < 10 || !bytes[..10].is_ascii() || bytes[4] != b'-' || bytes[7] != b'-' {
+        return None;
+    }
+    let year = parse_digits(&s[0..4])?;
+    let month = parse_digits(&s[5..7])?;
+    let day = parse_digits(&s[8..10])?;
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    if bytes.len() == 10 {
+        return Some(Value::Date(days as i32));
+    }
+
+    let time = s[10..].strip_prefix([' ', 'T'])?;
+    let time = time.strip_suffix('Z').unwrap_or(time);
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let time = time.as_bytes();
+    if time.len() != 8 || time[2] != b':' || time[5] != b':' {
+        return None;
+    }
+    let text = std::str::from_utf8(time).ok()?;
+    let (hour, minute, second) = (
+        parse_digits(&text[0..2])?,
+        parse_digits(&text[3..5])?,
+        parse_digits(&text[6..8])?,
+    );
+    if hour > 23 || minute > 59 || second > 59 || fraction.len() > 6 {
+        return None;
+    }
+    let micros = if fraction.is_empty() {
+        0
+    } else {
+        parse_digits(fraction)? * 10i64.pow(6 - fraction.len() as u32)
+    };
+    let seconds = hour * 3600 + minute * 60 + second;
+    Some(Value::Timestamp(
+        days * MICROS_PER_DAY + seconds * MICROS_PER_SECOND + micros,
+    ))
+}
+
+fn parse_digits(s: &str) -> Option<i64> {
+    if s.bytes().all(|b| b.is_ascii_digit()) {
+        s.parse().ok()
+    } else {
+        None
+    }
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01 of a proleptic Gregorian date (Howard Hinnant's algorithm)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Inverse of `days_from_civil`
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimal(s: &str) -> Value {
+        match Value::parse(s) {
+            value @ Value::Decimal(..) => value,
+            other => panic!("{} parsed as {:?}", s, other),
+        }
+    }
+
+    #[test]
+    fn parse_infers_types() {
+        assert_eq!(Value::parse(""), Value::Null);
+        assert_eq!(Value::parse("  "), Value::Null);
+        assert_eq!(Value::parse("42"), Value::Int64(42));
+        assert_eq!(Value::parse(" -7 "), Value::Int64(-7));
+        assert_eq!(Value::parse("1.50"), Value::Decimal(150, 2));
+        assert_eq!(Value::parse("-.5"), Value::Decimal(-5, 1));
+        assert_eq!(
+            Value::parse("9223372036854775808"),
+            Value::Decimal(9_223_372_036_854_775_808, 0)
+        );
+        assert_eq!(Value::parse("1e3"), Value::Float64(1000.0));
+        assert_eq!(Value::parse("inf"), Value::Float64(f64::INFINITY));
+        assert!(matches!(Value::parse("NaN"), Value::Float64(x) if x.is_nan()));
+        assert_eq!(Value::parse("TRUE"), Value::Bool(true));
+        assert_eq!(Value::parse("false"), Value::Bool(false));
+        assert_eq!(Value::parse("1970-01-02"), Value::Date(1));
+        assert_eq!(
+            Value::parse("1970-01-01T00:00:01.5Z"),
+            Value::Timestamp(1_500_000)
+        );
+        for text in [
+            "infinity",
+            "1.2.3",
+            "-",
+            ".",
+            "N/A",
+            "2024-02-30",
+            "2024-01-01 25:00:00",
+        ] {
+            assert_eq!(Value::parse(text), Value::Text(text.to_string()));
+        }
+    }
+
+    #[test]
+    fn parse_keeps_non_ascii_text_that_looks_like_a_date() {
+        for text in [
+            "2024-01-0é",
+            "2024-0é-01",
+            "é024-01-01",
+            "2024-01-01 1é:00:00",
+        ] {
+            assert_eq!(Value::parse(text), Value::Text(text.to_string()));
+        }
+    }
+
+    #[test]
+    fn compare_orders_across_numeric_types() {
+        let cases = [
+            ("1", "1.0", Some(Ordering::Equal)),
+            ("2", "10", Some(Ordering::Less)),
+            ("0.1", "0.10", Some(Ordering::Equal)),
+            ("1.5", "1e0", Some(Ordering::Greater)),
+            (
+                "9007199254740993",
+                "9007199254740992",
+                Some(Ordering::Greater),
+            ),
+            ("b", "a", Some(Ordering::Greater)),
+            ("false", "true", Some(Ordering::Less)),
+            ("2024-01-01", "2024-01-01 00:00:00", Some(Ordering::Equal)),
+            ("2024-01-01 00:00:01", "2024-01-02", Some(Ordering::Less)),
+            ("1", "a", None),
+            ("2024-01-01", "1", None),
+            ("", "", None),
+            ("NaN", "1", None),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(
+                Value::parse(a).compare(&Value::parse(b)),
+                expected,
+                "{} vs {}",
+                a,
+                b
+            );
+        }
+    }
+
+    #[test]
+    fn total_order_ranks_types_that_do_not_compare() {
+        let ordered = [
+            "-1",
+            "2.5",
+            "NaN",
+            "false",
+            "true",
+            "2024-01-01",
+            "A",
+            "a",
+            "",
+        ];
+        for (i, a) in ordered.iter().enumerate() {
+            for (j, b) in ordered.iter().enumerate() {
+                let (a, b) = (Value::parse(a), Value::parse(b));
+                assert_eq!(a.total_order(&b), i.cmp(&j), "{:?} vs {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn decimal_arithmetic_is_exact() {
+        let sum = decimal("0.1").add(&decimal("0.2")).unwrap();
+        assert_eq!(sum.to_string(), "0.3");
+        assert_eq!(sum.compare(&decimal("0.3")), Some(Ordering::Equal));
+        assert_eq!(
+            decimal("1.25")
+                .subtract(&Value::Int64(2))
+                .unwrap()
+                .to_string(),
+            "-0.75"
+        );
+        assert_eq!(
+            decimal("1.5")
+                .multiply(&decimal("-0.2"))
+                .unwrap()
+                .to_string(),
+            "-0.30"
+        );
+        assert_eq!(
+            decimal("7.5")
+                .remainder(&Value::Int64(2))
+                .unwrap()
+                .to_string(),
+            "1.5"
+        );
+        assert_eq!(decimal("-0.05").to_string(), "-0.05");
+        assert_eq!(
+            Value::Int64(1).divide(&Value::Int64(4)),
+            Some(Value::Float64(0.25))
+        );
+        assert_eq!(Value::Int64(1).divide(&decimal("0.0")), Some(Value::Null));
+        assert_eq!(
+            Value::Int64(1).remainder(&Value::Int64(0)),
+            Some(Value::Null)
+        );
+        // Integers that overflow become decimals instead of wrapping
+        assert_eq!(
+            Value::Int64(i64::MAX)
+                .add(&Value::Int64(1))
+                .unwrap()
+                .to_string(),
+            "9223372036854775808"
+        );
+        assert_eq!(Value::Text("a".to_string()).add(&Value::Int64(1)), None);
+    }
+
+    #[test]
+    fn equality_key_matches_compare() {
+        let equal = [
+            ("1", "1.0"),
+            ("1", "1e0"),
+            ("0.50", "0.5"),
+            ("2024-01-01", "2024-01-01 00:00:00"),
+        ];
+        for (a, b) in equal {
+            assert_eq!(
+                Value::parse(a).equality_key(),
+                Value::parse(b).equality_key()
+            );
+        }
+        assert_ne!(
+            Value::parse("1").equality_key(),
+            Value::parse("true").equality_key()
+        );
+        assert_eq!(Value::parse("NaN").equality_key(), None);
+    }
+
+    #[test]
+    fn dates_round_trip() {
+        for text in [
+            "1970-01-01",
+            "1969-12-31",
+            "2000-02-29",
+            "1900-03-01",
+            "2024-12-31",
+            "0001-01-01",
+            "9999-12-31",
+        ] {
+            assert_eq!(Value::parse(text).to_string(), text);
+        }
+        for days in -800_000..800_000 {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        for text in [
+            "2024-02-29 23:59:59",
+            "1969-12-31 23:59:59.000001",
+            "2024-01-01 00:00:00",
+        ] {
+            assert_eq!(Value::parse(text).to_string(), text);
+        }
+        assert_eq!(
+            Value::parse("2024-01-01T12:30:00Z").to_string(),
+            "2024-01-01 12:30:00"
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use csvsql_v2_benchmark::{
+    execute, functions, parse_query, Aggregate, CsvConfig, SortConfig, Value,
+};
+use std::env;
+use std::fs;
+
+// Geometric mean of the positive values of a group
+#[derive(Debug, Default)]
+struct GeoMean {
+    log_sum: f64,
+    count: i64,
+}
+
+impl Aggregate for GeoMean {
+    fn apply(&mut self, value: &Value) {
+        if let Some(x) = value.to_f64().filter(|x| *x > 0.0) {
+            self.log_sum += x.ln();
+            self.count += 1;
+        }
+    }
+
+    fn result(&self) -> Value {
+        if self.count == 0 {
+            return Value::Null;
+        }
+        Value::Float64((self.log_sum / self.count as f64).exp())
+    }
+
+    fn state(&self) -> Vec<Value> {
+        vec![Value::Float64(self.log_sum), Value::Int64(self.count)]
+    }
+
+    fn merge_state(&mut self, state: &[Value]) -> Result<(), String> {
+        match state {
+            [Value::Float64(log_sum), Value::Int64(count)] => {
+                self.log_sum += log_sum;
+                self.count += count;
+                Ok(())
+            }
+            _ => Err("Invalid partial state for GEOMEAN".to_string()),
+        }
+    }
+}
+
+fn run(sql: &str) -> Result<String, String> {
+    let query = parse_query(sql).map_err(|err| err.to_string())?;
+    let csv_config = CsvConfig {
+        delimiter: None,
+        quote: b'"',
+        escape: None,
+        has_header: true,
+        columns: None,
+        null_values: Vec::new(),
+    };
+    let sort_config = SortConfig {
+        memory_budget: 1024 * 1024,
+        temp_dir: env::temp_dir(),
+    };
+    let mut out = Vec::new();
+    execute(&query, &csv_config, &sort_config, &mut out).map_err(|err| err.to_string())?;
+    Ok(String::from_utf8(out).unwrap())
+}
+
+#[test]
+fn registered_aggregate_is_parsed_validated_and_executed() {
+    functions()
+        .write()
+        .unwrap()
+        .register("geomean", GeoMean::default);
+
+    let path = env::temp_dir().join(format!("csvsql-geomean-{}.csv", std::process::id()));
+    fs::write(
+        &path,
+        "region,price\neast,2\neast,8\nwest,3\nwest,\nwest,27\nnorth,5\n",
+    )
+    .unwrap();
+    let file = path.display();
+
+    let output = run(&format!(
+        "SELECT region, GEOMEAN(price) * 2 AS doubled FROM {} GROUP BY region \
+         HAVING GEOMEAN(price) > 4 ORDER BY GEOMEAN(price) DESC",
+        file
+    ));
+    let output = output.unwrap();
+    let mut lines = output.lines();
+    assert_eq!(lines.next(), Some("region,doubled"));
+    let rows: Vec<(&str, f64)> = lines
+        .map(|line| {
+            let (region, doubled) = line.split_once(',').unwrap();
+            (region, doubled.parse().unwrap())
+        })
+        .collect();
+    assert_eq!(rows.len(), 2);
+    assert_eq!((rows[0].0, rows[1].0), ("west", "north"));
+    assert!((rows[0].1 - 18.0).abs() < 1e-9);
+    assert!((rows[1].1 - 10.0).abs() < 1e-9);
+
+    // The registered function counts as an aggregate when checking grouped columns
+    let output = run(&format!("SELECT region, GEOMEAN(price) FROM {}", file));
+    assert_eq!(
+        output.unwrap_err(),
+        "Column must appear in GROUP BY or be used in an aggregate function: region"
+    );
+
+    let output = run(&format!("SELECT GEOMEAN(price, 2) FROM {}", file));
+    assert_eq!(output.unwrap_err(), "Too many arguments for GEOMEAN");
+
+    fs::remove_file(&path).unwrap();
+}